@@ -4,15 +4,18 @@ use std::error::Error;
 fn main() -> Result<(), Box<dyn Error>> {
     // build.rs is run from the workspace root.
     println!("cargo:rustc-link-arg=-Tkernel/kernel.ld");
-    // Define number of cpus for the link script.
-    let ncpu = var("NCPU").unwrap_or("1".into());
-    ncpu.parse::<usize>().expect("NCPU must be a number");
-    println!("cargo:rustc-link-arg=--defsym=NCPU={ncpu}");
+    // Qemu's -bios none jumps to the start of RAM. OpenSBI keeps the first
+    // 2 MB for itself and jumps past it.
+    let base = if var("CARGO_FEATURE_SBI").is_ok() {
+        "0x80200000"
+    } else {
+        "0x80000000"
+    };
+    println!("cargo:rustc-link-arg=--defsym=BASE_ADDRESS={base}");
     // Set max-page-size = 4K for riscv64.
     println!("cargo:rustc-link-arg=-z");
     println!("cargo:rustc-link-arg=max-page-size=4096");
     // Force a rebuild if the linker script changes.
     println!("cargo:rerun-if-changed=kernel.ld");
-    println!("cargo:rerun-if-env-changed=NCPU");
     Ok(())
 }
@@ -1,7 +1,14 @@
 mod riscv;
 
+#[cfg(feature = "sbi")]
+pub use riscv::sbi;
+
 pub trait Arch {
-    fn start(_: fn() -> !) -> !;
+    /// Switch to supervisor mode and call `main(arg)`.
+    fn start(main: fn(usize) -> !, arg: usize) -> !;
+    /// Start `cpu` if the firmware keeps it stopped. It enters the kernel at
+    /// _entry like the boot cpu did, and main() gets `arg`.
+    fn start_cpu(cpu: usize, arg: usize);
     fn cpuid() -> usize;
     fn interrupts_enabled() -> bool;
     fn enable_interrupts();
@@ -11,6 +18,10 @@ pub trait Arch {
     /// will halt indefinitely.
     fn halt() -> !;
     fn page_size() -> usize;
+    /// Returns the current value of the free-running real-time counter.
+    fn time() -> usize;
+    /// Ask for a timer interrupt once `time()` reaches `deadline`.
+    fn set_timer(deadline: usize);
 }
 
 pub type CurrentArch = riscv::RiscVArch;
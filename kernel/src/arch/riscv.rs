@@ -1,9 +1,13 @@
 use core::arch::asm;
 
+#[cfg(feature = "sbi")]
+pub mod sbi;
+
 pub struct RiscVArch;
 
 impl super::Arch for RiscVArch {
-    fn start(main: fn() -> !) -> ! {
+    #[cfg(not(feature = "sbi"))]
+    fn start(main: fn(usize) -> !, arg: usize) -> ! {
         // Set Machine Exception Program Counter to main(), for mret.
         w_mepc(main as usize);
 
@@ -24,7 +28,7 @@ impl super::Arch for RiscVArch {
         // Allow supervisor to access time, stimecmp register.
         w_mcounteren(r_mcounteren() | MCOUNTEREN_TM);
         // Ask for the very first timer interrupt.
-        w_stimecmp(r_time() + 1000000);
+        Self::set_timer(r_time() + 1000000);
 
         // Configure Physical Memory Protection to give supervisor mode
         // access to all of physical memory.
@@ -41,11 +45,42 @@ impl super::Arch for RiscVArch {
         x |= MSTATUS_MPP_S;
         w_mstatus(x);
         unsafe {
-            // Switch to supervisor mode and jump to main().
-            asm!("mret", options(noreturn));
+            // Switch to supervisor mode and jump to main(arg).
+            asm!("mv a0, {}", "mret", in(reg) arg, options(noreturn));
         }
     }
 
+    #[cfg(feature = "sbi")]
+    fn start(main: fn(usize) -> !, arg: usize) -> ! {
+        // The firmware has already switched to supervisor mode, delegated
+        // interrupts and exceptions, and given us access to physical memory.
+        // asm/entry_sbi.S put the hartid in tp.
+
+        // Disable paging for now.
+        w_satp(0);
+
+        // Enable external and timer interrupt.
+        w_sie(r_sie() | SIE_SEIE | SIE_STIE);
+        // Ask for the very first timer interrupt.
+        Self::set_timer(r_time() + 1000000);
+
+        main(arg)
+    }
+
+    #[cfg(not(feature = "sbi"))]
+    fn start_cpu(_cpu: usize, _arg: usize) {
+        // Every hart runs _entry at reset, there is nothing to start.
+    }
+
+    #[cfg(feature = "sbi")]
+    fn start_cpu(cpu: usize, arg: usize) {
+        unsafe extern "C" {
+            fn _entry();
+        }
+        // Fails harmlessly if the hart is already running.
+        sbi::hart_start(cpu, _entry as usize, arg);
+    }
+
     #[inline(always)]
     fn cpuid() -> usize {
         r_tp()
@@ -83,6 +118,21 @@ impl super::Arch for RiscVArch {
     fn page_size() -> usize {
         4096
     }
+
+    #[inline(always)]
+    fn time() -> usize {
+        r_time()
+    }
+
+    #[cfg(not(feature = "sbi"))]
+    fn set_timer(deadline: usize) {
+        w_stimecmp(deadline);
+    }
+
+    #[cfg(feature = "sbi")]
+    fn set_timer(deadline: usize) {
+        sbi::set_timer(deadline);
+    }
 }
 
 // Generate assembly for reading a csr.
@@ -0,0 +1,106 @@
+/// Calls into the Supervisor Binary Interface, implemented by the machine
+/// mode firmware (OpenSBI under qemu). See the RISC-V SBI specification v2.0.
+///
+/// Only used when the kernel is built with the `sbi` feature and boots in
+/// supervisor mode.
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// Extension ids.
+const EID_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x54494d45;
+const EID_IPI: usize = 0x735049;
+pub const EID_HSM: usize = 0x48534d;
+const EID_SRST: usize = 0x53525354;
+const EID_DBCN: usize = 0x4442434e;
+
+// Function ids.
+const BASE_PROBE_EXTENSION: usize = 3;
+const TIME_SET_TIMER: usize = 0;
+const IPI_SEND_IPI: usize = 0;
+pub const HSM_HART_START: usize = 0;
+pub const HSM_HART_STOP: usize = 1;
+const SRST_SYSTEM_RESET: usize = 0;
+const DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+/// `system_reset()` types.
+pub const RESET_SHUTDOWN: usize = 0;
+pub const RESET_COLD_REBOOT: usize = 1;
+
+/// `system_reset()` reasons.
+pub const RESET_REASON_NONE: usize = 0;
+pub const RESET_REASON_FAILURE: usize = 1;
+
+// How console_putchar() reaches the firmware: not probed yet, the debug
+// console extension, or the legacy putchar call.
+const CONSOLE_UNKNOWN: u8 = 0;
+const CONSOLE_DBCN: u8 = 1;
+const CONSOLE_LEGACY: u8 = 2;
+static CONSOLE: AtomicU8 = AtomicU8::new(CONSOLE_UNKNOWN);
+
+/// Ask for a supervisor timer interrupt once `time` reaches `stime`.
+pub fn set_timer(stime: usize) {
+    ecall(EID_TIME, TIME_SET_TIMER, stime, 0, 0);
+}
+
+/// Send a supervisor software interrupt to the harts in `hart_mask`. Bit i
+/// of the mask stands for hart `hart_mask_base + i`.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> bool {
+    ecall(EID_IPI, IPI_SEND_IPI, hart_mask, hart_mask_base, 0).0 == 0
+}
+
+/// Start the stopped hart `hartid` in supervisor mode at `start_addr`, with
+/// its hartid in a0 and `opaque` in a1. Returns false if the firmware
+/// refused, e.g. because the hart is already running.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> bool {
+    ecall(EID_HSM, HSM_HART_START, hartid, start_addr, opaque).0 == 0
+}
+
+/// Shut down or reboot the machine. Only returns if the firmware can't.
+pub fn system_reset(reset_type: usize, reason: usize) {
+    ecall(EID_SRST, SRST_SYSTEM_RESET, reset_type, reason, 0);
+}
+
+/// Write a byte to the firmware's console.
+pub fn console_putchar(c: u8) {
+    let mut how = CONSOLE.load(Ordering::Relaxed);
+    if how == CONSOLE_UNKNOWN {
+        how = if probe(EID_DBCN) {
+            CONSOLE_DBCN
+        } else {
+            CONSOLE_LEGACY
+        };
+        CONSOLE.store(how, Ordering::Relaxed);
+    }
+    if how == CONSOLE_DBCN {
+        ecall(EID_DBCN, DBCN_CONSOLE_WRITE_BYTE, c as usize, 0, 0);
+    } else {
+        ecall(EID_LEGACY_CONSOLE_PUTCHAR, 0, c as usize, 0, 0);
+    }
+}
+
+// Returns true if the firmware implements extension `eid`.
+fn probe(eid: usize) -> bool {
+    let (error, value) = ecall(EID_BASE, BASE_PROBE_EXTENSION, eid, 0, 0);
+    error == 0 && value != 0
+}
+
+// Call function `fid` of extension `eid`. Returns the error code (0 on
+// success) and the value.
+#[inline(always)]
+fn ecall(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    let error;
+    let value;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    (error, value)
+}
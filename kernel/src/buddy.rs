@@ -0,0 +1,241 @@
+/// Buddy allocator for physically contiguous runs of pages.
+///
+/// Memory between the end of the kernel and `memlayout::phystop()` is split
+/// into blocks of 2^order pages, each aligned to its own size. Freeing a block
+/// merges it with its buddy (the neighbouring block of the same order)
+/// whenever the buddy is free too, so large runs reappear as memory is
+/// released.
+use crate::arch::{self, Arch, CurrentArch};
+use crate::memlayout::{NPAGES, page_addr, page_index};
+use crate::spinlock::Spinlock;
+use core::cell::Cell;
+use core::ptr::null_mut;
+
+/// Largest supported order. A block of this order is 2^MAX_ORDER pages
+/// (4 MB with 4 KB pages).
+pub const MAX_ORDER: usize = 10;
+
+/// A physically contiguous run of 2^order pages.
+pub struct PhysPages {
+    pa: *mut u8,
+    order: usize,
+}
+
+impl PhysPages {
+    /// Returns the base address of the run.
+    pub fn get_addr(&self) -> usize {
+        arch::ptr_address(self.pa)
+    }
+
+    /// Returns a pointer to the base of the run.
+    pub fn get_ptr(&self) -> *mut u8 {
+        self.pa
+    }
+
+    /// Returns the order of the run.
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Returns the size of the run in bytes.
+    pub fn size(&self) -> usize {
+        CurrentArch::page_size() << self.order
+    }
+}
+
+impl Drop for PhysPages {
+    fn drop(&mut self) {
+        free_block(self.pa, self.order);
+    }
+}
+
+/// Allocate 2^order physically contiguous pages.
+/// Returns `Some(PhysPages)` if memory is available, otherwise return None.
+pub fn alloc_pages(order: usize) -> Option<PhysPages> {
+    let pa = alloc_block(order);
+    if pa.is_null() {
+        None
+    } else {
+        Some(PhysPages { pa, order })
+    }
+}
+
+/// Free memory statistics of the buddy allocator.
+#[derive(Copy, Clone)]
+pub struct BuddyStats {
+    /// Total number of pages managed by the allocator.
+    pub total_pages: usize,
+    /// Number of free pages.
+    pub free_pages: usize,
+    /// Number of free blocks of each order.
+    pub free_blocks: [usize; MAX_ORDER + 1],
+}
+
+/// Returns the current free memory statistics.
+pub fn stats() -> BuddyStats {
+    BUDDY.lock.with_lock(|| {
+        let mut free_blocks = [0; MAX_ORDER + 1];
+        for (order, n) in free_blocks.iter_mut().enumerate() {
+            *n = BUDDY.nfree[order].get();
+        }
+        BuddyStats {
+            total_pages: (BUDDY.end.get() - BUDDY.start.get()) / CurrentArch::page_size(),
+            free_pages: BUDDY.free_pages.get(),
+            free_blocks,
+        }
+    })
+}
+
+/// Hand the memory in `[pa_start, pa_end)` to the allocator. Both bounds
+/// must be page aligned.
+pub fn init(pa_start: usize, pa_end: usize) {
+    BUDDY.start.set(pa_start);
+    BUDDY.end.set(pa_end);
+    // Carve the range into the largest aligned blocks that fit.
+    let mut pa = pa_start;
+    while pa < pa_end {
+        let mut order = MAX_ORDER;
+        while order > 0
+            && (!page_index(pa).is_multiple_of(1 << order)
+                || pa + (CurrentArch::page_size() << order) > pa_end)
+        {
+            order -= 1;
+        }
+        free_block(pa as *mut u8, order);
+        pa += CurrentArch::page_size() << order;
+    }
+}
+
+/// Allocate a block of 2^order pages and return its base, or null if no block
+/// is available. Used by `kalloc` to refill its per-cpu caches.
+pub fn alloc_block(order: usize) -> *mut u8 {
+    if order > MAX_ORDER {
+        return null_mut();
+    }
+    BUDDY.lock.with_lock(|| {
+        // Find the smallest free block that is large enough.
+        let mut cur = order;
+        while cur <= MAX_ORDER && BUDDY.heads[cur].get().is_null() {
+            cur += 1;
+        }
+        if cur > MAX_ORDER {
+            return null_mut();
+        }
+        let block = BUDDY.heads[cur].get();
+        BUDDY.remove(block, cur);
+        // Split it, giving the upper halves back until it has the right size.
+        while cur > order {
+            cur -= 1;
+            let upper = block as usize + (CurrentArch::page_size() << cur);
+            BUDDY.insert(upper as *mut FreeBlock, cur);
+        }
+        BUDDY.free_pages.set(BUDDY.free_pages.get() - (1 << order));
+        block as *mut u8
+    })
+}
+
+/// Return a block of 2^order pages starting at `pa` to the allocator, merging
+/// it with its buddies where possible.
+pub fn free_block(pa: *mut u8, order: usize) {
+    let pa = arch::ptr_address(pa);
+    let size = CurrentArch::page_size() << order;
+    if order > MAX_ORDER
+        || !page_index(pa).is_multiple_of(1 << order)
+        || pa < BUDDY.start.get()
+        || pa + size > BUDDY.end.get()
+    {
+        panic!("buddy free: 0x{:x} order {}", pa, order);
+    }
+
+    // Fill with junk to catch dangling refs.
+    unsafe {
+        (pa as *mut u8).write_bytes(1, size);
+    }
+
+    BUDDY.lock.with_lock(|| {
+        let mut idx = page_index(pa);
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = idx ^ (1 << order);
+            if buddy >= NPAGES || BUDDY.free_order[buddy].get() != order as u8 + 1 {
+                break;
+            }
+            BUDDY.remove(page_addr(buddy) as *mut FreeBlock, order);
+            idx = idx.min(buddy);
+            order += 1;
+        }
+        BUDDY.insert(page_addr(idx) as *mut FreeBlock, order);
+        BUDDY
+            .free_pages
+            .set(BUDDY.free_pages.get() + (size / CurrentArch::page_size()));
+    });
+}
+
+// Header written into the first page of every free block, linking it into the
+// free list of its order.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+struct Buddy {
+    lock: Spinlock,
+    // Managed range, set by `init()`.
+    start: Cell<usize>,
+    end: Cell<usize>,
+    // Doubly linked free list for each order.
+    heads: [Cell<*mut FreeBlock>; MAX_ORDER + 1],
+    // Number of blocks on each free list.
+    nfree: [Cell<usize>; MAX_ORDER + 1],
+    // Total number of free pages.
+    free_pages: Cell<usize>,
+    // For every page: order + 1 if the page starts a free block, 0 otherwise.
+    // Lets `free_block()` find out whether a buddy is free in O(1).
+    free_order: [Cell<u8>; NPAGES],
+}
+
+unsafe impl Sync for Buddy {}
+
+static BUDDY: Buddy = Buddy {
+    lock: Spinlock::new("buddy"),
+    start: Cell::new(0),
+    end: Cell::new(0),
+    heads: [const { Cell::new(null_mut()) }; MAX_ORDER + 1],
+    nfree: [const { Cell::new(0) }; MAX_ORDER + 1],
+    free_pages: Cell::new(0),
+    free_order: [const { Cell::new(0) }; NPAGES],
+};
+
+impl Buddy {
+    // Push `block` onto the free list of `order`. Caller must hold the lock.
+    fn insert(&self, block: *mut FreeBlock, order: usize) {
+        let head = self.heads[order].get();
+        unsafe {
+            (*block).next = head;
+            (*block).prev = null_mut();
+            if !head.is_null() {
+                (*head).prev = block;
+            }
+        }
+        self.heads[order].set(block);
+        self.nfree[order].set(self.nfree[order].get() + 1);
+        self.free_order[page_index(block as usize)].set(order as u8 + 1);
+    }
+
+    // Unlink `block` from the free list of `order`. Caller must hold the lock.
+    fn remove(&self, block: *mut FreeBlock, order: usize) {
+        unsafe {
+            let (next, prev) = ((*block).next, (*block).prev);
+            if prev.is_null() {
+                self.heads[order].set(next);
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+        self.nfree[order].set(self.nfree[order].get() - 1);
+        self.free_order[page_index(block as usize)].set(0);
+    }
+}
@@ -6,7 +6,7 @@ pub struct Buf {
     valid: bool,
     // Does disk "own" buf?
     pub disk: bool,
-    dev: u32,
+    pub dev: u32,
     pub blockno: u32,
     refcnt: u32,
     pub data: [u8; BSIZE],
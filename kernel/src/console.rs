@@ -7,12 +7,15 @@
 ///     control-d -- endof file
 ///     control-p -- print process list
 use crate::drivers::uart;
+use crate::memlayout;
 
 // Erase the last output character.
 const BACKSPACE: u16 = 0x100;
 
 pub fn consoleinit() {
-    uart::uartinit();
+    if memlayout::has_uart() {
+        uart::uartinit();
+    }
 }
 
 /// Send one character to the uart, but don't use interrupts or sleep. Safe to
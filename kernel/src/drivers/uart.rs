@@ -1,4 +1,6 @@
 // Low-level driver for 16550a UART.
+#[cfg(feature = "sbi")]
+use crate::arch::sbi;
 use crate::kstate;
 use crate::kutils::without_interrupts;
 use crate::memlayout;
@@ -31,6 +33,12 @@ pub fn uartinit() {
 /// register to be empty.
 pub fn putc_sync(c: u8) {
     let transmit = || {
+        if !memlayout::has_uart() {
+            // Without a uart only the firmware, if any, can print.
+            #[cfg(feature = "sbi")]
+            sbi::console_putchar(c);
+            return;
+        }
         // Wait for UART to set Transmit Holding Empty in LSR.
         while (read_reg(LSR) & LSR_TX_IDLE) == 0 {}
         write_reg(THR, c);
@@ -51,11 +59,11 @@ pub fn putc_sync(c: u8) {
     }
 }
 
-// The UART control registers are memory-mapped at address `memlayout::UART0`.
+// The UART control registers are memory-mapped at address `memlayout::uart0()`.
 // This macro returns the address of one of the registers.
 #[inline(always)]
 fn reg(reg: u8) -> usize {
-    memlayout::UART0 + reg as usize
+    memlayout::uart0() + reg as usize
 }
 
 #[inline(always)]
@@ -0,0 +1,237 @@
+/// Driver for qemu's virtio disk device. Uses qemu's mmio interface to virtio.
+///
+/// Up to `param::NDISK` disks are supported. They are numbered in the order
+/// `virtio_probe()` finds them, and `Buf::dev` selects the disk a request goes
+/// to.
+///
+/// If the device has several request queues (`VIRTIO_BLK_F_MQ`, e.g. qemu's
+/// `num-queues=`), each hart submits to its own queue, up to one per hart.
+/// A request holds its queue's lock until it completes, since `sleep()` can't
+/// release it yet, so requests on one queue still run one at a time. Each
+/// request is a chain of three buffers, in an indirect table if the device
+/// supports them.
+use super::constants::*;
+use super::queue::{VirtqBuf, Virtqueue};
+use super::transport::MmioTransport;
+use crate::arch::{self, Arch, CurrentArch};
+use crate::buf::Buf;
+use crate::channel::Channel;
+use crate::memlayout;
+use crate::param;
+use crate::proc::{sleep, wakeup};
+use crate::spinlock::Spinlock;
+use core::mem::size_of;
+use kernelapi::fs::BSIZE;
+
+/// Set up the disk behind `transport`. Returns its device number, or `None`
+/// if `param::NDISK` disks are already in use.
+pub fn virtio_disk_init(transport: MmioTransport) -> Option<usize> {
+    let dev = unsafe {
+        let ndisk = &raw mut NDISK_FOUND;
+        if *ndisk == param::NDISK {
+            return None;
+        }
+        *ndisk += 1;
+        *ndisk - 1
+    };
+    let disk = unsafe { &raw mut DISKS[dev] };
+    unsafe { (*disk).transport = transport };
+
+    transport.begin_init();
+    let features = transport.negotiate(
+        (1 << VIRTIO_BLK_F_MQ)
+            | (1 << VIRTIO_RING_F_INDIRECT_DESC)
+            | (1 << VIRTIO_RING_F_EVENT_IDX),
+        "disk",
+    );
+
+    // One queue per hart, if the device has that many.
+    let mut nqueue = 1;
+    if features & (1 << VIRTIO_BLK_F_MQ) != 0 {
+        let lo = transport.read_config(VIRTIO_BLK_CONFIG_NUM_QUEUES) as usize;
+        let hi = transport.read_config(VIRTIO_BLK_CONFIG_NUM_QUEUES + 1) as usize;
+        nqueue = (lo | (hi << 8)).clamp(1, memlayout::ncpu());
+    }
+    unsafe {
+        (*disk).nqueue = nqueue;
+        for q in 0..nqueue {
+            (*disk).queues[q].vq = Virtqueue::new(features);
+            transport.setup_queue(q as u32, &(*disk).queues[q].vq, "disk");
+        }
+    }
+
+    transport.driver_ok();
+
+    // plic.rs and trap.rs arrange for interrupts from the disk's irq.
+    Some(dev)
+}
+
+/// Returns the number of disks found by `virtio_probe()`.
+pub fn virtio_disk_count() -> usize {
+    let ndisk = &raw const NDISK_FOUND;
+    unsafe { *ndisk }
+}
+
+pub fn virtio_disk_rw(buf: *mut Buf, write: bool) {
+    let dev = unsafe { (*buf).dev } as usize;
+    if dev >= virtio_disk_count() {
+        panic!("virtio_disk_rw: no disk {}", dev);
+    }
+    let sector = unsafe { ((*buf).blockno as usize * (BSIZE / SSIZE)) as u64 };
+    let disk = unsafe { &raw mut DISKS[dev] };
+    // Any queue works, use this hart's own.
+    let q = unsafe { CurrentArch::cpuid() % (*disk).nqueue };
+    let queue = unsafe { &raw mut (*disk).queues[q] };
+    let tk = unsafe { (*queue).lock.acquire() };
+
+    // The virtio spec's Section 5.2 says that legacy block operations use
+    // three descriptors: one for type/reserved/sector, one for the data,
+    // one for a 1-byte status result.
+
+    // Allocate the three descriptors.
+    let head = loop {
+        match unsafe { (*queue).vq.alloc_chain(3) } {
+            Some(head) => break head,
+            None => sleep(Channel::VirtioDescFree),
+        }
+    };
+
+    // Format the three descriptors. Qemu's virtio-blk.c reads them.
+    let buf0_addr = unsafe {
+        let buf0 = &mut (*queue).ops[head as usize];
+        if write {
+            buf0.r#type = VIRTIO_BLK_T_OUT;
+        } else {
+            buf0.r#type = VIRTIO_BLK_T_IN;
+        }
+        buf0.reserved = 0;
+        buf0.sector = sector;
+        arch::ptr_address(buf0 as *const VirtioBlkReq)
+    };
+    let status_addr = unsafe {
+        let status = &mut (*queue).info[head as usize].status;
+        *status = 0xff;
+        arch::ptr_address(status)
+    };
+    let bufs = [
+        VirtqBuf {
+            addr: buf0_addr as u64,
+            len: size_of::<VirtioBlkReq>() as u32,
+            device_writes: false,
+        },
+        VirtqBuf {
+            addr: arch::ptr_address(unsafe { &(*buf).data }) as u64,
+            len: BSIZE as u32,
+            // Device reads from buf.data for writes, writes to it for reads.
+            device_writes: !write,
+        },
+        // Device writes the status.
+        VirtqBuf {
+            addr: status_addr as u64,
+            len: 1,
+            device_writes: true,
+        },
+    ];
+
+    // Record the struct buf for virtio_disk_intr().
+    unsafe {
+        (*buf).disk = true;
+        (*queue).info[head as usize].buf = buf;
+        (*queue).vq.fill_chain(head, &bufs);
+        (*queue).vq.submit(head);
+        if (*queue).vq.needs_notify() {
+            (*disk).transport.notify(q as u32);
+        }
+    }
+
+    // Wait for virtio_disk_intr() to say request has finished.
+    while unsafe { (*buf).disk } {
+        sleep(Channel::VirtioReqFinished);
+    }
+
+    // Cleanup.
+    unsafe {
+        (*queue).info[head as usize].buf = core::ptr::null_mut();
+        (*queue).vq.free_chain(head);
+        (*queue).lock.release(tk);
+    }
+    wakeup(Channel::VirtioDescFree);
+}
+
+/// Handle an interrupt from disk `dev`.
+pub fn virtio_disk_intr(dev: usize) {
+    let disk = unsafe { &raw mut DISKS[dev] };
+    unsafe { (*disk).transport.ack_interrupt() };
+
+    // The device doesn't say which queue is done, look at all of them.
+    for q in 0..unsafe { (*disk).nqueue } {
+        let queue = unsafe { &raw mut (*disk).queues[q] };
+        let tk = unsafe { (*queue).lock.acquire() };
+        unsafe {
+            while let Some((id, _)) = (*queue).vq.pop_used() {
+                let info = &mut (*queue).info[id as usize];
+                if info.status != 0 {
+                    panic!("virtio_disk_intr status");
+                }
+
+                // Disk is done with buf.
+                (*info.buf).disk = false;
+                wakeup(Channel::VirtioReqFinished);
+            }
+            (*queue).lock.release(tk);
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DiskInfo {
+    buf: *mut Buf,
+    status: u8,
+}
+
+// One request queue of a disk.
+struct DiskQueue {
+    vq: Virtqueue,
+    // Track info about in-flight operations, for use when completion interrupt
+    // arrives. Indexed by first descriptor index of chain.
+    info: [DiskInfo; NUM],
+    // Disk command headers. One-for-one with descriptors, for convenience.
+    ops: [VirtioBlkReq; NUM],
+    // Spinlock to guard the queue.
+    lock: Spinlock,
+}
+
+struct Disk {
+    transport: MmioTransport,
+    // Request queues, `nqueue` of them in use.
+    queues: [DiskQueue; param::NCPU],
+    nqueue: usize,
+}
+
+unsafe impl Sync for Disk {}
+
+// transport and queues are initialized in `virtio_disk_init()`.
+static mut DISKS: [Disk; param::NDISK] = [const { Disk::new() }; param::NDISK];
+// Number of entries of `DISKS` in use.
+static mut NDISK_FOUND: usize = 0;
+
+impl Disk {
+    const fn new() -> Self {
+        Disk {
+            transport: MmioTransport::new(0),
+            queues: [const {
+                DiskQueue {
+                    vq: Virtqueue::uninit(),
+                    info: [DiskInfo {
+                        buf: core::ptr::null_mut(),
+                        status: 0,
+                    }; NUM],
+                    ops: [VirtioBlkReq::new(); NUM],
+                    lock: Spinlock::new("vdisk_lock"),
+                }
+            }; param::NCPU],
+            nqueue: 0,
+        }
+    }
+}
@@ -4,18 +4,23 @@
 /// The virtio spec:
 /// https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
 
-/// Virtio mmio control registers, mapped starting at 0x10001000, from qemu's
+/// Virtio mmio control registers, mapped starting at each transport's base
+/// address (see `memlayout::virtio_mmio()`), from qemu's
 /// virtio_mmio.h
 /// Magic number - 0x74726976
 pub const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
 /// Version: should be 2.
 pub const VIRTIO_MMIO_VERSION: usize = 0x004;
-/// Device type; 1 is net, 2 is disk.
+/// Device type; 1 is net, 2 is disk, 4 is entropy source.
 pub const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
 /// Should be 0x554d4551.
 pub const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
 pub const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+/// Selects which 32 feature bits `VIRTIO_MMIO_DEVICE_FEATURES` shows.
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
 pub const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+/// Selects which 32 feature bits `VIRTIO_MMIO_DRIVER_FEATURES` sets.
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
 /// Select queue, write-only.
 pub const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
 /// Max size of current queue, read-only.
@@ -40,6 +45,13 @@ pub const VIRTIO_MMIO_DRIVER_DESC_HIGH: usize = 0x094;
 /// write-only.
 pub const VIRTIO_MMIO_DEVICE_DESC_LOW: usize = 0x0a0;
 pub const VIRTIO_MMIO_DEVICE_DESC_HIGH: usize = 0x0a4;
+/// Device specific configuration space starts here.
+pub const VIRTIO_MMIO_CONFIG: usize = 0x100;
+
+/// Device types found in `VIRTIO_MMIO_DEVICE_ID`. 0 means no device.
+pub const VIRTIO_ID_NET: u32 = 1;
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+pub const VIRTIO_ID_RNG: u32 = 4;
 
 /// Status register bits, from qemu's virtio_config.h
 pub const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
@@ -59,6 +71,11 @@ pub const VIRTIO_BLK_F_MQ: usize = 12;
 pub const VIRTIO_F_ANY_LAYOUT: usize = 27;
 pub const VIRTIO_RING_F_INDIRECT_DESC: usize = 28;
 pub const VIRTIO_RING_F_EVENT_IDX: usize = 29;
+/// Device complies with the virtio 1.0 spec. Lives in the second feature
+/// word, so this is bit 0 of that word.
+pub const VIRTIO_F_VERSION_1: usize = 32;
+/// Network device has a MAC address in config space.
+pub const VIRTIO_NET_F_MAC: usize = 5;
 
 /// This many virtio descriptors, must be a power of two.
 /// This must also fit into a `u16` as that is the maximum index allowed in the
@@ -79,6 +96,10 @@ pub struct VirtqDesc {
 pub const VRING_DESC_F_NEXT: u16 = 1;
 /// Device writes (vs read).
 pub const VRING_DESC_F_WRITE: u16 = 2;
+/// Buffer holds a table of descriptors.
+pub const VRING_DESC_F_INDIRECT: u16 = 4;
+/// Device asks the driver not to notify it, in `VirtqUsed::flags`.
+pub const VRING_USED_F_NO_NOTIFY: u16 = 1;
 
 /// the (entire) avail ring, from the spec.
 #[repr(C, packed)]
@@ -89,7 +110,9 @@ pub struct VirtqAvail {
     pub idx: u16,
     /// Descriptor numbers of chain heads.
     pub ring: [u16; NUM],
-    pub unused: u16,
+    /// With `VIRTIO_RING_F_EVENT_IDX`, the device interrupts once its used
+    /// idx passes this.
+    pub used_event: u16,
 }
 
 /// One entry in the "used" ring, with which the device tells the driver about
@@ -108,10 +131,15 @@ pub struct VirtqUsed {
     /// Device increments when it adds a ring[] entry.
     pub idx: u16,
     pub ring: [VirtqUsedElem; NUM],
+    /// With `VIRTIO_RING_F_EVENT_IDX`, the driver notifies once its avail idx
+    /// passes this.
+    pub avail_event: u16,
 }
 
 /// These are specific to virtio block devices, e.g. disks, described in Section
 /// 5.2 of the spec.
+/// Offset of `num_queues` (u16) in the config space, with `VIRTIO_BLK_F_MQ`.
+pub const VIRTIO_BLK_CONFIG_NUM_QUEUES: usize = 34;
 /// Read the disk.
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 /// Write the disk.
@@ -137,3 +165,24 @@ impl VirtioBlkReq {
         }
     }
 }
+
+/// These are specific to virtio network devices, described in Section 5.1 of
+/// the spec.
+/// Receive queue index.
+pub const VIRTIO_NET_RX_QUEUE: u32 = 0;
+/// Transmit queue index.
+pub const VIRTIO_NET_TX_QUEUE: u32 = 1;
+
+/// Header preceding every packet on both virtio-net queues. Its size is 12
+/// bytes because `VIRTIO_F_VERSION_1` is negotiated.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+    pub num_buffers: u16,
+}
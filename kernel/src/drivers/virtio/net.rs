@@ -0,0 +1,264 @@
+/// Driver for qemu's virtio network device, with a raw ethernet frame
+/// interface. Uses qemu's mmio interface to virtio.
+///
+/// Queue 0 receives and queue 1 transmits. Every descriptor owns one buffer
+/// holding a `VirtioNetHdr` followed by the frame. Received frames are copied
+/// into a small packet pool by `virtio_net_intr()`, where `virtio_net_recv()`
+/// picks them up.
+use super::constants::*;
+use super::queue::{VirtqBuf, Virtqueue};
+use super::transport::MmioTransport;
+use crate::arch;
+use crate::spinlock::{Spinlock, SpinlockToken};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+/// Largest ethernet frame we send or receive, without the FCS.
+pub const MAX_FRAME: usize = 1514;
+
+// Size of each descriptor's buffer: header plus a full frame.
+const BUF_SIZE: usize = 2048;
+const HDR_SIZE: usize = size_of::<VirtioNetHdr>();
+
+// Received frames waiting for `virtio_net_recv()`. Further frames are dropped.
+const RX_POOL: usize = 32;
+
+/// Set up the network device behind `transport`.
+pub fn virtio_net_init(transport: MmioTransport) {
+    let net = &raw mut NET;
+    unsafe { (*net).transport = transport };
+
+    transport.begin_init();
+    // We only want the MAC address from config space, and virtio 1.0 so the
+    // header layout is fixed.
+    let features = transport.negotiate(1 << VIRTIO_NET_F_MAC, "net");
+    if features & (1 << VIRTIO_F_VERSION_1) == 0 {
+        panic!("virtio net is not virtio 1.0");
+    }
+
+    unsafe {
+        if features & (1 << VIRTIO_NET_F_MAC) != 0 {
+            for (i, b) in (*net).mac.iter_mut().enumerate() {
+                *b = transport.read_config(i);
+            }
+        }
+
+        (*net).rx.init(features);
+        transport.setup_queue(VIRTIO_NET_RX_QUEUE, &(*net).rx.vq, "net");
+        (*net).tx.init(features);
+        transport.setup_queue(VIRTIO_NET_TX_QUEUE, &(*net).tx.vq, "net");
+
+        // Hand every receive buffer to the device. Receive descriptors always
+        // belong to the device, they are never freed.
+        while let Some(id) = (*net).rx.vq.alloc_chain(1) {
+            let buf = VirtqBuf {
+                addr: (*net).rx.buf_addr(id as usize),
+                len: BUF_SIZE as u32,
+                // Device writes the packet.
+                device_writes: true,
+            };
+            (*net).rx.vq.fill_chain(id, &[buf]);
+            (*net).rx.vq.submit(id);
+        }
+    }
+
+    transport.driver_ok();
+    transport.notify(VIRTIO_NET_RX_QUEUE);
+
+    unsafe { (*net).ready = true };
+
+    // plic.rs and trap.rs arrange for interrupts from the device's irq.
+}
+
+/// Returns true once `virtio_net_init()` has set up the device.
+pub fn virtio_net_ready() -> bool {
+    let net = &raw const NET;
+    unsafe { (*net).ready }
+}
+
+/// Returns the MAC address of the network device.
+pub fn virtio_net_mac() -> [u8; 6] {
+    let net = &raw const NET;
+    unsafe { (*net).mac }
+}
+
+/// Queue an ethernet frame for transmission.
+/// Returns false if there is no network device, the frame is too large or all
+/// transmit buffers are busy.
+pub fn virtio_net_send(frame: &[u8]) -> bool {
+    if !virtio_net_ready() || frame.len() > MAX_FRAME {
+        return false;
+    }
+    let net = &raw mut NET;
+    let tk = unsafe { (*net).lock.acquire() };
+    unsafe {
+        // Reclaim buffers the device has finished sending.
+        (*net).reap_tx(&tk);
+        let id = match (*net).tx.vq.alloc_chain(1) {
+            Some(id) => id,
+            None => {
+                (*net).lock.release(tk);
+                return false;
+            }
+        };
+
+        // An all zero header asks for no offloads.
+        let buf = (*net).tx.buf(id as usize);
+        buf[..HDR_SIZE].fill(0);
+        buf[HDR_SIZE..HDR_SIZE + frame.len()].copy_from_slice(frame);
+
+        let buf = VirtqBuf {
+            addr: (*net).tx.buf_addr(id as usize),
+            len: (HDR_SIZE + frame.len()) as u32,
+            // Device reads the packet.
+            device_writes: false,
+        };
+        (*net).tx.vq.fill_chain(id, &[buf]);
+        (*net).tx.vq.submit(id);
+        (*net).transport.notify(VIRTIO_NET_TX_QUEUE);
+        (*net).lock.release(tk);
+    }
+    true
+}
+
+/// Copy the oldest received frame into `buf` and return its length, or `None`
+/// if no frame is waiting or there is no network device. Frames longer than
+/// `buf` are truncated.
+/// Also collects completed receive buffers itself, so it works with device
+/// interrupts off.
+pub fn virtio_net_recv(buf: &mut [u8]) -> Option<usize> {
+    if !virtio_net_ready() {
+        return None;
+    }
+    let net = &raw mut NET;
+    let tk = unsafe { (*net).lock.acquire() };
+    let frame = unsafe {
+        (*net).reap_rx(&tk);
+        let frame = (*net).pool.pop_front();
+        (*net).lock.release(tk);
+        frame
+    };
+    frame.map(|frame| {
+        let n = frame.len().min(buf.len());
+        buf[..n].copy_from_slice(&frame[..n]);
+        n
+    })
+}
+
+/// Returns the number of received frames dropped because the pool was full.
+pub fn virtio_net_dropped() -> usize {
+    let net = &raw const NET;
+    unsafe { (*net).dropped }
+}
+
+pub fn virtio_net_intr() {
+    let net = &raw mut NET;
+    let tk = unsafe { (*net).lock.acquire() };
+    unsafe {
+        // Acknowledge first, so completions that race with us raise a new
+        // interrupt.
+        (*net).transport.ack_interrupt();
+        (*net).reap_rx(&tk);
+        (*net).reap_tx(&tk);
+        (*net).lock.release(tk);
+    }
+}
+
+// One virtqueue of the network device, with a buffer per descriptor.
+struct NetQueue {
+    vq: Virtqueue,
+    // Packet buffers. One-for-one with descriptors.
+    bufs: Vec<Box<[u8]>>,
+}
+
+impl NetQueue {
+    const fn uninit() -> Self {
+        NetQueue {
+            vq: Virtqueue::uninit(),
+            bufs: Vec::new(),
+        }
+    }
+
+    // Allocate the rings and buffers.
+    fn init(&mut self, features: u64) {
+        self.vq = Virtqueue::new(features);
+        self.bufs = (0..NUM)
+            .map(|_| vec![0u8; BUF_SIZE].into_boxed_slice())
+            .collect();
+    }
+
+    // Returns the packet buffer of descriptor `id`.
+    fn buf(&mut self, id: usize) -> &mut [u8] {
+        &mut self.bufs[id]
+    }
+
+    // Returns the physical address of the packet buffer of descriptor `id`.
+    fn buf_addr(&self, id: usize) -> u64 {
+        arch::ptr_address(self.bufs[id].as_ptr()) as u64
+    }
+}
+
+struct Net {
+    transport: MmioTransport,
+    rx: NetQueue,
+    tx: NetQueue,
+    mac: [u8; 6],
+    // Received frames, oldest first.
+    pool: VecDeque<Vec<u8>>,
+    // Frames dropped because `pool` was full.
+    dropped: usize,
+    // Has `virtio_net_init()` run?
+    ready: bool,
+    // Spinlock to guard the device.
+    lock: Spinlock,
+}
+
+unsafe impl Sync for Net {}
+
+// transport and queues are initialized in `virtio_net_init()`.
+static mut NET: Net = Net {
+    transport: MmioTransport::new(0),
+    rx: NetQueue::uninit(),
+    tx: NetQueue::uninit(),
+    mac: [0; 6],
+    pool: VecDeque::new(),
+    dropped: 0,
+    ready: false,
+    lock: Spinlock::new("vnet_lock"),
+};
+
+impl Net {
+    // Move received frames into the pool and give their buffers back to the
+    // device.
+    fn reap_rx(&mut self, _: &SpinlockToken) {
+        let mut refilled = false;
+        while let Some((id, len)) = self.rx.vq.pop_used() {
+            // Don't trust the device to stay within the buffer.
+            let len = (len as usize).min(BUF_SIZE);
+            if len > HDR_SIZE {
+                if self.pool.len() < RX_POOL {
+                    let frame = self.rx.buf(id as usize)[HDR_SIZE..len].to_vec();
+                    self.pool.push_back(frame);
+                } else {
+                    self.dropped += 1;
+                }
+            }
+            // Give the buffer back to the device.
+            self.rx.vq.submit(id);
+            refilled = true;
+        }
+        if refilled {
+            self.transport.notify(VIRTIO_NET_RX_QUEUE);
+        }
+    }
+
+    // Free the transmit descriptors the device has finished with.
+    fn reap_tx(&mut self, _: &SpinlockToken) {
+        while let Some((id, _)) = self.tx.vq.pop_used() {
+            self.tx.vq.free_chain(id);
+        }
+    }
+}
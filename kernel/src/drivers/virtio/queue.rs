@@ -0,0 +1,356 @@
+/// A split virtqueue (virtio spec Section 2.6), independent of the device.
+///
+/// The driver allocates a chain of descriptors, points them at its buffers
+/// and puts the head of the chain on the avail ring. The device processes
+/// the chain and returns its head on the used ring. The caller serializes
+/// access, usually under the lock of its device, and tells the device about
+/// new buffers with `MmioTransport::notify()` when `needs_notify()` says so.
+///
+/// With `VIRTIO_RING_F_INDIRECT_DESC` a chain lives in a separate table and
+/// takes a single descriptor of the ring. With `VIRTIO_RING_F_EVENT_IDX` the
+/// driver and device tell each other exactly when they want a notification
+/// or interrupt, instead of getting one per request.
+use super::constants::*;
+use crate::arch::{self, Arch, CurrentArch};
+use crate::kalloc::{PhysPage, kalloc};
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::Ordering;
+use core::sync::atomic::fence;
+
+// Most buffers in one indirect table. Longer chains use ring descriptors.
+const INDIRECT_MAX: usize = 8;
+
+/// One buffer of a descriptor chain.
+#[derive(Copy, Clone)]
+pub struct VirtqBuf {
+    /// Physical address.
+    pub addr: u64,
+    pub len: u32,
+    /// Does the device write the buffer (vs read it)?
+    pub device_writes: bool,
+}
+
+pub struct Virtqueue {
+    // A set (not a ring) of DMA descriptors, with which the driver tells the
+    // device where to read and write individual operations. There are `NUM`
+    // descriptors. Most operations consist of a "chain" (a linked list) of a
+    // couple of these descriptors.
+    desc: VSList<[VirtqDesc; NUM]>,
+    // A ring in which the driver writes descriptor numbers that the driver
+    // would like the device to process. It only includes the head descriptor
+    // of each chain. The ring has `NUM` elements.
+    avail: VSList<VirtqAvail>,
+    // A ring in which the device writes descriptor numbers that the device has
+    // finished processing (just the head of each chain). There are `NUM` used
+    // ring entries.
+    used: VSList<VirtqUsed>,
+    // An indirect descriptor table per ring descriptor, if the device
+    // supports them.
+    indirect: VSList<[[VirtqDesc; INDIRECT_MAX]; NUM]>,
+
+    // Our own book-keeping.
+    // Is a descriptor free?
+    free: [bool; NUM],
+    // We've looked this far in `used.ring`.
+    used_idx: u16,
+    // Avail idx the last time the device was notified.
+    notified_idx: u16,
+    // Was `VIRTIO_RING_F_EVENT_IDX` negotiated?
+    event_idx: bool,
+}
+
+impl Virtqueue {
+    /// A queue without memory, for statics. `new()` replaces it before use.
+    pub const fn uninit() -> Self {
+        Virtqueue {
+            desc: VSList::uninit(),
+            avail: VSList::uninit(),
+            used: VSList::uninit(),
+            indirect: VSList::uninit(),
+            free: [false; NUM],
+            used_idx: 0,
+            notified_idx: 0,
+            event_idx: false,
+        }
+    }
+
+    /// Allocate and zero the rings, using the ring `features` negotiated
+    /// with the device. All `NUM` descriptors start out unused.
+    pub fn new(features: u64) -> Self {
+        let alloc = || match kalloc() {
+            Some(page) => page,
+            None => panic!("virtio queue kalloc"),
+        };
+        let indirect = if features & (1 << VIRTIO_RING_F_INDIRECT_DESC) != 0 {
+            VSList::new(alloc())
+        } else {
+            VSList::uninit()
+        };
+        Virtqueue {
+            desc: VSList::new(alloc()),
+            avail: VSList::new(alloc()),
+            used: VSList::new(alloc()),
+            indirect,
+            free: [true; NUM],
+            used_idx: 0,
+            notified_idx: 0,
+            event_idx: features & (1 << VIRTIO_RING_F_EVENT_IDX) != 0,
+        }
+    }
+
+    /// Physical addresses of the descriptor table, avail ring and used ring,
+    /// for the transport to hand to the device.
+    pub fn addrs(&self) -> (usize, usize, usize) {
+        (
+            self.desc.get_page().get_addr(),
+            self.avail.get_page().get_addr(),
+            self.used.get_page().get_addr(),
+        )
+    }
+
+    /// Allocate a chain for `n` buffers and return its head. That is one
+    /// descriptor if the chain fits in an indirect table, otherwise `n`
+    /// descriptors (they need not be contiguous). Returns `None` if there
+    /// aren't enough free descriptors.
+    pub fn alloc_chain(&mut self, n: usize) -> Option<u16> {
+        if n == 0 {
+            panic!("virtqueue alloc_chain");
+        }
+        if n > 1 && n <= INDIRECT_MAX && self.indirect.page.is_some() {
+            let head = self.alloc_desc()?;
+            let desc = &mut self.desc.as_mut_ref()[head as usize];
+            desc.flags = VRING_DESC_F_INDIRECT;
+            desc.len = (n * size_of::<VirtqDesc>()) as u32;
+            return Some(head);
+        }
+        // Link the descriptors from the tail, so the chain is whole at every
+        // step and can be freed as such.
+        let mut head = self.alloc_desc()?;
+        for _ in 1..n {
+            let Some(id) = self.alloc_desc() else {
+                self.free_chain(head);
+                return None;
+            };
+            let desc = &mut self.desc.as_mut_ref()[id as usize];
+            desc.flags = VRING_DESC_F_NEXT;
+            desc.next = head;
+            head = id;
+        }
+        Some(head)
+    }
+
+    /// Point the chain at `head`, allocated by `alloc_chain(bufs.len())`, at
+    /// `bufs` in that order.
+    pub fn fill_chain(&mut self, head: u16, bufs: &[VirtqBuf]) {
+        let flags = |buf: &VirtqBuf| {
+            if buf.device_writes {
+                VRING_DESC_F_WRITE
+            } else {
+                0
+            }
+        };
+        let desc = &mut self.desc.as_mut_ref()[head as usize];
+        if desc.flags & VRING_DESC_F_INDIRECT != 0 {
+            if desc.len as usize != bufs.len() * size_of::<VirtqDesc>() {
+                panic!("virtqueue fill_chain");
+            }
+            let table = &mut self.indirect.as_mut_ref()[head as usize];
+            desc.addr = arch::ptr_address(table.as_ptr()) as u64;
+            // Within a table the chain is in order.
+            for (i, buf) in bufs.iter().enumerate() {
+                table[i].addr = buf.addr;
+                table[i].len = buf.len;
+                table[i].flags = flags(buf);
+                table[i].next = 0;
+                if i + 1 < bufs.len() {
+                    table[i].flags |= VRING_DESC_F_NEXT;
+                    table[i].next = (i + 1) as u16;
+                }
+            }
+            return;
+        }
+        let mut id = head;
+        for (i, buf) in bufs.iter().enumerate() {
+            let desc = &mut self.desc.as_mut_ref()[id as usize];
+            let has_next = desc.flags & VRING_DESC_F_NEXT != 0;
+            if has_next != (i + 1 < bufs.len()) {
+                panic!("virtqueue fill_chain");
+            }
+            desc.addr = buf.addr;
+            desc.len = buf.len;
+            desc.flags = flags(buf) | if has_next { VRING_DESC_F_NEXT } else { 0 };
+            id = desc.next;
+        }
+    }
+
+    /// Put the chain starting at `head` on the avail ring. A chain may be
+    /// submitted again once the device has returned it.
+    pub fn submit(&mut self, head: u16) {
+        // Tell the device the first index in our chain of descriptors.
+        let idx = (self.avail.as_ref().idx % (NUM as u16)) as usize;
+        self.avail.as_mut_ref().ring[idx] = head;
+
+        fence(Ordering::SeqCst);
+        // Tell the device another avail ring entry is available.
+        let next = self.avail.as_ref().idx.wrapping_add(1);
+        self.avail.as_mut_ref().idx = next;
+        fence(Ordering::SeqCst);
+    }
+
+    /// Allocate a chain for `bufs`, fill it in and submit it. Returns the head
+    /// of the chain, or `None` if there aren't enough free descriptors.
+    pub fn add_buf(&mut self, bufs: &[VirtqBuf]) -> Option<u16> {
+        let head = self.alloc_chain(bufs.len())?;
+        self.fill_chain(head, bufs);
+        self.submit(head);
+        Some(head)
+    }
+
+    /// Returns true if the device wants to hear about the chains submitted
+    /// since the last call. The caller then notifies it.
+    pub fn needs_notify(&mut self) -> bool {
+        // Make sure the device sees the new avail idx before we look at what
+        // it asked for.
+        fence(Ordering::SeqCst);
+        let new = self.avail.as_ref().idx;
+        let old = self.notified_idx;
+        self.notified_idx = new;
+        if self.event_idx {
+            // The device wants a notification once avail idx moves past
+            // avail_event, see vring_need_event() in the spec.
+            let event = unsafe { ptr::read_volatile(&raw const (*self.used()).avail_event) };
+            new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+        } else {
+            let flags = unsafe { ptr::read_volatile(&raw const (*self.used()).flags) };
+            flags & VRING_USED_F_NO_NOTIFY == 0
+        }
+    }
+
+    /// Take the next chain the device has finished with off the used ring.
+    /// Returns its head and the number of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        // The device increments used.idx when it adds an entry to the used
+        // ring.
+        if self.used_idx == self.device_used_idx() {
+            if !self.event_idx {
+                return None;
+            }
+            // Ask for an interrupt when the next entry arrives, then look
+            // again in case it arrived meanwhile.
+            self.avail.as_mut_ref().used_event = self.used_idx;
+            fence(Ordering::SeqCst);
+            if self.used_idx == self.device_used_idx() {
+                return None;
+            }
+        }
+        fence(Ordering::SeqCst);
+        let ring_id = self.used_idx as usize % NUM;
+        let elem = &self.used.as_ref().ring[ring_id];
+        let (id, len) = (elem.id as u16, elem.len);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        Some((id, len))
+    }
+
+    /// Free a chain of descriptors.
+    pub fn free_chain(&mut self, mut idx: u16) {
+        loop {
+            let desc = &self.desc.as_ref()[idx as usize];
+            let (flags, next) = (desc.flags, desc.next);
+            self.free_desc(idx);
+            if flags & VRING_DESC_F_NEXT != 0 {
+                idx = next;
+            } else {
+                break;
+            }
+        }
+    }
+
+    // The used ring, which the device writes behind our back. Read the fields
+    // that we poll through it with volatile loads, so the compiler doesn't
+    // hoist them out of the caller's loop.
+    fn used(&self) -> *const VirtqUsed {
+        self.used.get_page().get_addr() as *const VirtqUsed
+    }
+
+    // The device's used.idx.
+    fn device_used_idx(&self) -> u16 {
+        unsafe { ptr::read_volatile(&raw const (*self.used()).idx) }
+    }
+
+    // Find a free descriptor, mark it non-free, return its index.
+    fn alloc_desc(&mut self) -> Option<u16> {
+        let i = self.free.iter().position(|&free| free)?;
+        self.free[i] = false;
+        Some(i as u16)
+    }
+
+    // Mark a descriptor as free.
+    fn free_desc(&mut self, idx: u16) {
+        let idx = idx as usize;
+        if idx >= NUM {
+            panic!("free_desc 1");
+        }
+        if self.free[idx] {
+            panic!("free_desc 2");
+        }
+
+        let desc = &mut self.desc.as_mut_ref()[idx];
+        desc.addr = 0;
+        desc.len = 0;
+        desc.flags = 0;
+        desc.next = 0;
+        self.free[idx] = true;
+    }
+}
+
+// Each ring lives in one page. Uses the riscv page size since
+// `CurrentArch::page_size()` is not const.
+const _: () = assert!(size_of::<[VirtqDesc; NUM]>() <= 4096);
+const _: () = assert!(size_of::<VirtqAvail>() <= 4096);
+const _: () = assert!(size_of::<VirtqUsed>() <= 4096);
+const _: () = assert!(size_of::<[[VirtqDesc; INDIRECT_MAX]; NUM]>() <= 4096);
+
+// A wrapper class that owns a page worth of memory and provides a functional
+// interface of a list (or ring) of virtq device structs.
+struct VSList<T: Sized> {
+    page: Option<PhysPage>,
+    _ph: PhantomData<T>,
+}
+
+impl<T> VSList<T> {
+    const fn uninit() -> Self {
+        VSList {
+            page: None,
+            _ph: PhantomData,
+        }
+    }
+
+    fn new(page: PhysPage) -> Self {
+        unsafe {
+            page.get_ptr().write_bytes(0, CurrentArch::page_size());
+        }
+        VSList {
+            page: Some(page),
+            _ph: PhantomData,
+        }
+    }
+
+    fn get_page(&self) -> &PhysPage {
+        if self.page.is_none() {
+            panic!("virtio - tried to access before init");
+        }
+        self.page.as_ref().unwrap()
+    }
+
+    fn as_ref(&self) -> &T {
+        let s_ptr = self.get_page().get_addr() as *const T;
+        unsafe { &*s_ptr }
+    }
+
+    fn as_mut_ref(&mut self) -> &mut T {
+        let s_ptr = self.get_page().get_addr() as *mut T;
+        unsafe { &mut (*s_ptr) }
+    }
+}
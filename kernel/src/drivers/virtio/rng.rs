@@ -0,0 +1,109 @@
+/// Driver for qemu's virtio entropy device. Uses qemu's mmio interface to
+/// virtio.
+///
+/// The device has a single queue. The driver posts one device-writable buffer
+/// and the device fills it with random bytes. Requests are small and complete
+/// quickly, so `virtio_rng_read()` polls the used ring instead of sleeping.
+use super::queue::{VirtqBuf, Virtqueue};
+use super::transport::MmioTransport;
+use crate::arch;
+use crate::spinlock::Spinlock;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+
+// Largest request handed to the device at once.
+const BUF_SIZE: usize = 64;
+
+/// Set up the entropy device behind `transport`.
+pub fn virtio_rng_init(transport: MmioTransport) {
+    let rng = &raw mut RNG;
+    unsafe { (*rng).transport = transport };
+
+    transport.begin_init();
+    // The entropy device has no device specific features.
+    let features = transport.negotiate(0, "rng");
+
+    // Initialize queue 0.
+    unsafe {
+        (*rng).queue = Virtqueue::new(features);
+        transport.setup_queue(0, &(*rng).queue, "rng");
+        (*rng).buf = vec![0u8; BUF_SIZE];
+    }
+
+    transport.driver_ok();
+
+    unsafe { (*rng).ready = true };
+}
+
+/// Returns true once `virtio_rng_init()` has set up the device.
+pub fn virtio_rng_ready() -> bool {
+    let rng = &raw const RNG;
+    unsafe { (*rng).ready }
+}
+
+/// Fill `buf` with random bytes from the device and return how many bytes
+/// were written. The device may return fewer bytes than asked for.
+pub fn virtio_rng_read(buf: &mut [u8]) -> usize {
+    if !virtio_rng_ready() {
+        return 0;
+    }
+    let rng = &raw mut RNG;
+    let tk = unsafe { (*rng).lock.acquire() };
+    let want = buf.len().min(BUF_SIZE);
+
+    let len = unsafe {
+        // One descriptor is enough, only ever one request is in flight.
+        let req = VirtqBuf {
+            addr: arch::ptr_address((*rng).buf.as_ptr()) as u64,
+            len: want as u32,
+            // Device writes the random bytes.
+            device_writes: true,
+        };
+        if (*rng).queue.add_buf(&[req]).is_none() {
+            panic!("virtio_rng_read");
+        }
+        (*rng).transport.notify(0);
+
+        // Wait for the device to fill the buffer.
+        let (id, len) = loop {
+            match (*rng).queue.pop_used() {
+                Some(used) => break used,
+                None => spin_loop(),
+            }
+        };
+        (*rng).queue.free_chain(id);
+
+        // Nobody waits for the interrupt, just acknowledge it.
+        (*rng).transport.ack_interrupt();
+        (len as usize).min(want)
+    };
+
+    unsafe {
+        buf[..len].copy_from_slice(&(&(*rng).buf)[..len]);
+        (*rng).lock.release(tk);
+    }
+    len
+}
+
+struct Rng {
+    transport: MmioTransport,
+    queue: Virtqueue,
+    // The device writes random bytes here.
+    buf: Vec<u8>,
+    // Has `virtio_rng_init()` run?
+    ready: bool,
+    // Spinlock to guard the device.
+    lock: Spinlock,
+}
+
+unsafe impl Sync for Rng {}
+
+// transport, queue and buf are initialized in `virtio_rng_init()`.
+static mut RNG: Rng = Rng {
+    transport: MmioTransport::new(0),
+    queue: Virtqueue::uninit(),
+    buf: Vec::new(),
+    ready: false,
+    lock: Spinlock::new("vrng_lock"),
+};
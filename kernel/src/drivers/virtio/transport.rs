@@ -0,0 +1,145 @@
+/// The virtio-mmio transport (virtio spec Section 4.2), independent of the
+/// device behind it.
+///
+/// A driver starts the device with `begin_init()`, `negotiate()`, one
+/// `setup_queue()` per virtqueue and `driver_ok()`, following the order in
+/// Section 3.1.1 of the spec.
+use super::constants::*;
+use super::queue::Virtqueue;
+
+#[derive(Copy, Clone)]
+pub struct MmioTransport {
+    // Address of the device's mmio registers.
+    base: usize,
+}
+
+impl MmioTransport {
+    pub const fn new(base: usize) -> Self {
+        MmioTransport { base }
+    }
+
+    /// Returns the device id of a valid virtio 1.0 transport, 0 meaning
+    /// nothing is plugged in, or `None` if this isn't one.
+    pub fn device_id(&self) -> Option<u32> {
+        if self.read_reg(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+            || self.read_reg(VIRTIO_MMIO_VERSION) != 2
+            || self.read_reg(VIRTIO_MMIO_VENDOR_ID) != 0x554d4551
+        {
+            return None;
+        }
+        Some(self.read_reg(VIRTIO_MMIO_DEVICE_ID))
+    }
+
+    /// Reset the device and tell it a driver has found it.
+    pub fn begin_init(&self) {
+        let mut status = 0u32;
+        // Reset device.
+        self.write_reg(VIRTIO_MMIO_STATUS, status);
+
+        // Set ACKNOWLEDGE status bit.
+        status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+        self.write_reg(VIRTIO_MMIO_STATUS, status);
+
+        // Set DRIVER status bit.
+        status |= VIRTIO_CONFIG_S_DRIVER;
+        self.write_reg(VIRTIO_MMIO_STATUS, status);
+    }
+
+    /// Accept the features in `wanted` that the device offers, plus
+    /// `VIRTIO_F_VERSION_1` if it offers that. Returns the accepted features.
+    /// `name` identifies the device in panics.
+    pub fn negotiate(&self, wanted: u64, name: &str) -> u64 {
+        let wanted = wanted | (1 << VIRTIO_F_VERSION_1);
+        let mut features = 0u64;
+        for sel in 0..2 {
+            self.write_reg(VIRTIO_MMIO_DEVICE_FEATURES_SEL, sel);
+            let word = self.read_reg(VIRTIO_MMIO_DEVICE_FEATURES) & (wanted >> (sel * 32)) as u32;
+            self.write_reg(VIRTIO_MMIO_DRIVER_FEATURES_SEL, sel);
+            self.write_reg(VIRTIO_MMIO_DRIVER_FEATURES, word);
+            features |= (word as u64) << (sel * 32);
+        }
+
+        // Tell device that feature negotiation is complete.
+        let status = self.read_reg(VIRTIO_MMIO_STATUS) | VIRTIO_CONFIG_S_FEATURES_OK;
+        self.write_reg(VIRTIO_MMIO_STATUS, status);
+
+        // Re-read status to ensure FEATURES_OK is set.
+        if (self.read_reg(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK) == 0 {
+            panic!("virtio {} FEATURES_OK unset", name);
+        }
+        features
+    }
+
+    /// Tell the device where the rings of queue `q` are and enable it.
+    pub fn setup_queue(&self, q: u32, vq: &Virtqueue, name: &str) {
+        self.write_reg(VIRTIO_MMIO_QUEUE_SEL, q);
+
+        // Ensure queue is not in use.
+        if self.read_reg(VIRTIO_MMIO_QUEUE_READY) != 0 {
+            panic!("virtio {} should not be ready", name);
+        }
+
+        // Check maximum queue size.
+        let max = self.read_reg(VIRTIO_MMIO_QUEUE_NUM_MAX);
+        if max == 0 {
+            panic!("virtio {} has no queue {}", name, q);
+        }
+        if (max as usize) < NUM {
+            panic!("virtio {} max queue too short", name);
+        }
+
+        // Write physical addresses.
+        let (desc, avail, used) = vq.addrs();
+        self.write_reg(VIRTIO_MMIO_QUEUE_DESC_LOW, desc as u32);
+        self.write_reg(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc >> 32) as u32);
+        self.write_reg(VIRTIO_MMIO_DRIVER_DESC_LOW, avail as u32);
+        self.write_reg(VIRTIO_MMIO_DRIVER_DESC_HIGH, (avail >> 32) as u32);
+        self.write_reg(VIRTIO_MMIO_DEVICE_DESC_LOW, used as u32);
+        self.write_reg(VIRTIO_MMIO_DEVICE_DESC_HIGH, (used >> 32) as u32);
+
+        // Set queue size.
+        self.write_reg(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+        // Queue is ready.
+        self.write_reg(VIRTIO_MMIO_QUEUE_READY, 0x1);
+    }
+
+    /// Tell the device we're completely ready.
+    pub fn driver_ok(&self) {
+        let status = self.read_reg(VIRTIO_MMIO_STATUS) | VIRTIO_CONFIG_S_DRIVER_OK;
+        self.write_reg(VIRTIO_MMIO_STATUS, status);
+    }
+
+    /// Tell the device queue `q` has new buffers.
+    pub fn notify(&self, q: u32) {
+        // Value is the queue number.
+        self.write_reg(VIRTIO_MMIO_QUEUE_NOTIFY, q);
+    }
+
+    /// Acknowledge the device's interrupt. The device won't raise another
+    /// interrupt until we do. This may race with the device writing new
+    /// entries to a used ring, in which case the caller may process them now
+    /// and have nothing to do in the next interrupt, which is harmless.
+    pub fn ack_interrupt(&self) {
+        let status = self.read_reg(VIRTIO_MMIO_INTERRUPT_STATUS) & 0x3;
+        self.write_reg(VIRTIO_MMIO_INTERRUPT_ACK, status);
+    }
+
+    /// Read byte `off` of the device specific configuration space.
+    pub fn read_config(&self, off: usize) -> u8 {
+        unsafe { ((self.base + VIRTIO_MMIO_CONFIG + off) as *const u8).read_volatile() }
+    }
+
+    // The VIRTIO control registers are memory-mapped starting at `base`, see
+    // `memlayout::virtio_mmio()`.
+    #[inline(always)]
+    fn read_reg(&self, r: usize) -> u32 {
+        unsafe { ((self.base + r) as *const u32).read_volatile() }
+    }
+
+    #[inline(always)]
+    fn write_reg(&self, r: usize, v: u32) {
+        unsafe {
+            ((self.base + r) as *mut u32).write_volatile(v);
+        }
+    }
+}
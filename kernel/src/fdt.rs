@@ -0,0 +1,214 @@
+/// Flattened device tree (devicetree specification v0.4, chapter 5).
+///
+/// Qemu's boot ROM passes the address of a device tree blob describing the
+/// machine in register a1. This is a minimal read-only parser: it walks the
+/// structure block once and hands every node to a callback, which looks up
+/// the properties it cares about. `memlayout::init()` uses it to find RAM,
+/// harts and devices.
+use core::str;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+// Structure block tokens.
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+// Deepest node nesting we follow. Qemu's trees are 4 levels deep.
+const MAX_DEPTH: usize = 16;
+
+/// A device tree blob in memory.
+pub struct Fdt {
+    blob: &'static [u8],
+    structs: &'static [u8],
+    strings: &'static [u8],
+}
+
+/// One node of the tree, with its properties.
+pub struct Node {
+    /// Node name including the unit address, e.g. "uart@10000000".
+    pub name: &'static str,
+    // The node's property tokens.
+    props: &'static [u8],
+    strings: &'static [u8],
+    // #address-cells and #size-cells of the parent, used to decode "reg".
+    addr_cells: usize,
+    size_cells: usize,
+}
+
+impl Fdt {
+    /// Returns the device tree blob at physical address `pa`, or `None` if
+    /// there isn't a valid one.
+    pub fn new(pa: usize) -> Option<Fdt> {
+        if pa == 0 || pa % 8 != 0 {
+            return None;
+        }
+        // Read the fixed part of the header first to learn the total size.
+        let header = unsafe { core::slice::from_raw_parts(pa as *const u8, 40) };
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let size = be32(header, 4)? as usize;
+        let blob = unsafe { core::slice::from_raw_parts(pa as *const u8, size) };
+        let off_struct = be32(blob, 8)? as usize;
+        let off_strings = be32(blob, 12)? as usize;
+        let size_strings = be32(blob, 32)? as usize;
+        let size_struct = be32(blob, 36)? as usize;
+        Some(Fdt {
+            blob,
+            structs: blob.get(off_struct..off_struct.checked_add(size_struct)?)?,
+            strings: blob.get(off_strings..off_strings.checked_add(size_strings)?)?,
+        })
+    }
+
+    /// Returns the physical address range the blob occupies.
+    pub fn range(&self) -> (usize, usize) {
+        let start = self.blob.as_ptr() as usize;
+        (start, start + self.blob.len())
+    }
+
+    /// Call `f` on every node, parents before their children. Stops quietly
+    /// at anything malformed.
+    pub fn for_each_node(&self, mut f: impl FnMut(&Node)) {
+        // cells[d] are the #address-cells and #size-cells of the node at
+        // depth d - 1, i.e. those that apply to nodes at depth d. The root
+        // gets the defaults from the specification.
+        let mut cells = [(2, 1); MAX_DEPTH + 1];
+        let mut depth = 0;
+        let mut off = 0;
+        while let Some(token) = be32(self.structs, off) {
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let Some(name) = cstr(self.structs, off) else {
+                        return;
+                    };
+                    off = align4(off + name.len() + 1);
+                    // Properties come before any child node.
+                    let start = off;
+                    while let Some(token) = be32(self.structs, off) {
+                        match token {
+                            FDT_PROP => {
+                                let Some(len) = be32(self.structs, off + 4) else {
+                                    return;
+                                };
+                                off = align4(off + 12 + len as usize);
+                            }
+                            FDT_NOP => off += 4,
+                            _ => break,
+                        }
+                    }
+                    let Some(props) = self.structs.get(start..off) else {
+                        return;
+                    };
+                    if depth == MAX_DEPTH {
+                        return;
+                    }
+                    let node = Node {
+                        name,
+                        props,
+                        strings: self.strings,
+                        addr_cells: cells[depth].0,
+                        size_cells: cells[depth].1,
+                    };
+                    f(&node);
+                    depth += 1;
+                    cells[depth] = (
+                        node.prop_u32("#address-cells").unwrap_or(2) as usize,
+                        node.prop_u32("#size-cells").unwrap_or(1) as usize,
+                    );
+                }
+                FDT_END_NODE => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                FDT_NOP => {}
+                // FDT_END, or a property outside of a node.
+                _ => return,
+            }
+        }
+    }
+}
+
+impl Node {
+    /// Returns the value of property `name`.
+    pub fn prop(&self, name: &str) -> Option<&'static [u8]> {
+        let mut off = 0;
+        while let Some(token) = be32(self.props, off) {
+            if token == FDT_NOP {
+                off += 4;
+                continue;
+            }
+            let len = be32(self.props, off + 4)? as usize;
+            let nameoff = be32(self.props, off + 8)? as usize;
+            if cstr(self.strings, nameoff)? == name {
+                return self.props.get(off + 12..off + 12 + len);
+            }
+            off = align4(off + 12 + len);
+        }
+        None
+    }
+
+    /// Returns the first cell of property `name`.
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// Returns true if `compat` is one of the node's "compatible" strings.
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop("compatible")
+            .is_some_and(|v| v.split(|&b| b == 0).any(|s| s == compat.as_bytes()))
+    }
+
+    /// Returns the node's "device_type", e.g. "memory" or "cpu".
+    pub fn device_type(&self) -> Option<&'static str> {
+        let v = self.prop("device_type")?;
+        str::from_utf8(v.split(|&b| b == 0).next()?).ok()
+    }
+
+    /// Returns the (address, size) pairs of the node's "reg" property.
+    pub fn reg(&self) -> impl Iterator<Item = (usize, usize)> {
+        let (ac, sc) = (self.addr_cells, self.size_cells);
+        let entry = (ac + sc) * 4;
+        let v = match entry {
+            0 => &[],
+            _ => self.prop("reg").unwrap_or(&[]),
+        };
+        v.chunks_exact(entry.max(1))
+            .map(move |e| (cells(&e[..ac * 4]), cells(&e[ac * 4..])))
+    }
+
+    /// Returns the node's first interrupt number.
+    pub fn irq(&self) -> Option<u32> {
+        self.prop_u32("interrupts")
+    }
+}
+
+// Read a big-endian u32 at `off`.
+fn be32(buf: &[u8], off: usize) -> Option<u32> {
+    let b = buf.get(off..off.checked_add(4)?)?;
+    Some(u32::from_be_bytes(b.try_into().unwrap()))
+}
+
+// Combine big-endian cells into one number. Addresses and sizes are at most
+// two cells on a 64-bit machine.
+fn cells(buf: &[u8]) -> usize {
+    let (cells, _) = buf.as_chunks::<4>();
+    cells
+        .iter()
+        .fold(0, |n, c| (n << 32) | u32::from_be_bytes(*c) as usize)
+}
+
+// The NUL-terminated string at `off`.
+fn cstr(buf: &[u8], off: usize) -> Option<&str> {
+    let s = buf.get(off..)?;
+    let len = s.iter().position(|&b| b == 0)?;
+    str::from_utf8(&s[..len]).ok()
+}
+
+fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
@@ -1,6 +1,9 @@
 use crate::arch::{self, Arch, CurrentArch};
+use crate::buddy;
+use crate::kutils::without_interrupts;
 use crate::memlayout;
-use crate::spinlock::Spinlock;
+use crate::param;
+use crate::spinlock::{LockStats, Spinlock};
 use core::cell::Cell;
 
 /// A physical page of `CurrentArch::page_size()` bytes.
@@ -23,11 +26,15 @@ impl PhysPage {
 /// Allocate one page of physical memory.
 /// Returns `Some(PhysPage)` if memory is available, otherwise return None.
 pub fn kalloc() -> Option<PhysPage> {
-    let r = KMEM.lock.with_lock(|| {
-        let r = KMEM.free_list.get();
-        if !r.is_null() {
-            let next = unsafe { *(r as *mut *mut u8) };
-            KMEM.free_list.set(next);
+    // Interrupts must stay off while we use the cpuid.
+    let r = without_interrupts(|| {
+        let id = CurrentArch::cpuid();
+        let mut r = KMEM[id].pop();
+        if r.is_null() {
+            r = refill(id);
+        }
+        if r.is_null() {
+            r = steal(id);
         }
         r
     });
@@ -38,12 +45,34 @@ pub fn kalloc() -> Option<PhysPage> {
     }
 }
 
-/// Create a linked list of free physical pages.
+/// Hand all physical memory after the kernel to the buddy allocator. The
+/// per-cpu free lists start out empty and are refilled from it on demand.
 pub fn kinit() {
     let pa_start = arch::pg_round_up(end_addr());
-    let pa_end = memlayout::PHYSTOP - CurrentArch::page_size();
-    for pa in (pa_start..=pa_end).step_by(CurrentArch::page_size()) {
-        kfree(pa as *mut u8);
+    buddy::init(pa_start, memlayout::phystop());
+}
+
+/// Allocator counters of one cpu.
+#[derive(Copy, Clone)]
+pub struct KmemStats {
+    /// Pages on the cpu's free list.
+    pub nfree: usize,
+    /// Number of times the cpu took pages from another cpu's free list.
+    pub nsteal: usize,
+    /// Contention on the cpu's free list lock.
+    pub lock: LockStats,
+}
+
+/// Returns the allocator counters of `cpu`.
+pub fn kstats(cpu: usize) -> KmemStats {
+    let kmem = &KMEM[cpu];
+    let (nfree, nsteal) = kmem
+        .lock
+        .with_lock(|| (kmem.nfree.get(), kmem.nsteal.get()));
+    KmemStats {
+        nfree,
+        nsteal,
+        lock: kmem.lock.stats(),
     }
 }
 
@@ -53,17 +82,128 @@ impl Drop for PhysPage {
     }
 }
 
+// Maximum number of pages moved from another cpu's free list at once.
+const STEAL_BATCH: usize = 32;
+
+// A cpu refills its empty free list with a buddy block of this order.
+const REFILL_ORDER: usize = 5;
+
+// Pages freed beyond this many on a cpu's free list go back to the buddy
+// allocator so they can coalesce into larger blocks.
+const CACHE_MAX: usize = 2 << REFILL_ORDER;
+
+// A free list of physical pages. There is one per cpu.
 struct Kmem {
     lock: Spinlock,
     free_list: Cell<*mut u8>,
+    // Number of pages on `free_list`.
+    nfree: Cell<usize>,
+    // Number of successful steals from other cpus.
+    nsteal: Cell<usize>,
 }
 
 unsafe impl Sync for Kmem {}
 
-static KMEM: Kmem = Kmem {
-    lock: Spinlock::new("kalloc"),
-    free_list: Cell::new(core::ptr::null_mut()),
-};
+impl Kmem {
+    const fn new() -> Self {
+        Kmem {
+            lock: Spinlock::new("kmem"),
+            free_list: Cell::new(core::ptr::null_mut()),
+            nfree: Cell::new(0),
+            nsteal: Cell::new(0),
+        }
+    }
+
+    // Pop a page off the free list. Returns null if the list is empty.
+    fn pop(&self) -> *mut u8 {
+        self.lock.with_lock(|| {
+            let r = self.free_list.get();
+            if !r.is_null() {
+                let next = unsafe { *(r as *mut *mut u8) };
+                self.free_list.set(next);
+                self.nfree.set(self.nfree.get() - 1);
+            }
+            r
+        })
+    }
+
+    // Push the chain of `n` pages from `head` to `tail` onto the free list.
+    fn push_chain(&self, head: *mut u8, tail: *mut u8, n: usize) {
+        self.lock.with_lock(|| unsafe {
+            (tail as *mut *mut u8).write(self.free_list.get());
+            self.free_list.set(head);
+            self.nfree.set(self.nfree.get() + n);
+        });
+    }
+
+    // Detach up to half of the free list (at most `STEAL_BATCH` pages).
+    // Returns the head, tail and length of the detached chain.
+    fn take_batch(&self) -> (*mut u8, *mut u8, usize) {
+        self.lock.with_lock(|| {
+            let head = self.free_list.get();
+            if head.is_null() {
+                return (head, head, 0);
+            }
+            let want = (self.nfree.get() / 2).clamp(1, STEAL_BATCH);
+            let mut tail = head;
+            let mut n = 1;
+            while n < want {
+                tail = unsafe { *(tail as *mut *mut u8) };
+                n += 1;
+            }
+            let rest = unsafe { *(tail as *mut *mut u8) };
+            self.free_list.set(rest);
+            self.nfree.set(self.nfree.get() - n);
+            (head, tail, n)
+        })
+    }
+}
+
+static KMEM: [Kmem; param::NCPU] = [const { Kmem::new() }; param::NCPU];
+
+// Refill the free list of cpu `id` from the buddy allocator and return one
+// page, or null if the buddy allocator is out of memory.
+fn refill(id: usize) -> *mut u8 {
+    let mut order = REFILL_ORDER;
+    let mut block = buddy::alloc_block(order);
+    if block.is_null() {
+        // Memory is fragmented or nearly exhausted, settle for one page.
+        order = 0;
+        block = buddy::alloc_block(order);
+    }
+    if block.is_null() || order == 0 {
+        return block;
+    }
+    // Chain the remaining pages of the block together and cache them.
+    let n = (1 << order) - 1;
+    let page = |i: usize| (block as usize + i * CurrentArch::page_size()) as *mut u8;
+    for i in 1..n {
+        unsafe { (page(i) as *mut *mut u8).write(page(i + 1)) };
+    }
+    KMEM[id].push_chain(page(1), page(n), n);
+    block
+}
+
+// Refill the free list of cpu `id` from the other cpus and return one page,
+// or null if every list is empty. Only one lock is held at a time so two cpus
+// stealing from each other can't deadlock.
+fn steal(id: usize) -> *mut u8 {
+    for victim in (1..param::NCPU).map(|i| (id + i) % param::NCPU) {
+        let (head, tail, n) = KMEM[victim].take_batch();
+        if n == 0 {
+            continue;
+        }
+        let kmem = &KMEM[id];
+        kmem.lock
+            .with_lock(|| kmem.nsteal.set(kmem.nsteal.get() + 1));
+        if n > 1 {
+            let rest = unsafe { *(head as *mut *mut u8) };
+            kmem.push_chain(rest, tail, n - 1);
+        }
+        return head;
+    }
+    core::ptr::null_mut()
+}
 
 unsafe extern "C" {
     // First address after kernel. Defined by kernel.ld.
@@ -75,12 +215,13 @@ fn end_addr() -> usize {
     unsafe { arch::ptr_address(end.as_ptr()) }
 }
 
-// Free the page of physical memory pointed at by pa, which normally should
-// have been returned by a call to kalloc(). (The exception is when
-// initializing the allocator; see `kinit()` above.)
+// Free the page of physical memory pointed at by pa, which should have been
+// returned by a call to kalloc(). The page goes onto the free list of the
+// current cpu, or back to the buddy allocator if that list is full.
 fn kfree(pa: *mut u8) {
     let pa = arch::ptr_address(pa);
-    if pa % CurrentArch::page_size() != 0 || pa < end_addr() || pa >= memlayout::PHYSTOP {
+    if !pa.is_multiple_of(CurrentArch::page_size()) || pa < end_addr() || pa >= memlayout::phystop()
+    {
         panic!("kfree: 0x{:x}", pa);
     }
 
@@ -88,8 +229,15 @@ fn kfree(pa: *mut u8) {
     unsafe {
         pa.write_bytes(1, CurrentArch::page_size());
     }
-    KMEM.lock.with_lock(|| unsafe {
-        (pa as *mut *mut u8).write(KMEM.free_list.get());
-        KMEM.free_list.set(pa);
+    let cached = without_interrupts(|| {
+        let kmem = &KMEM[CurrentArch::cpuid()];
+        let full = kmem.lock.with_lock(|| kmem.nfree.get() >= CACHE_MAX);
+        if !full {
+            kmem.push_chain(pa, pa, 1);
+        }
+        !full
     });
+    if !cached {
+        buddy::free_block(pa, 0);
+    }
 }
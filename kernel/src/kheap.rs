@@ -0,0 +1,102 @@
+/// Kernel heap, so the kernel can use `alloc::boxed::Box`, `alloc::vec::Vec`
+/// and friends.
+///
+/// Small allocations are served from per-size-class slabs carved out of
+/// `kalloc()` pages. Anything larger than a slab object gets its own run of
+/// pages from the buddy allocator.
+use crate::arch::{Arch, CurrentArch};
+use crate::buddy;
+use crate::kalloc::kalloc;
+use crate::spinlock::Spinlock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::ptr::null_mut;
+
+// Object sizes of the slab classes: 16, 32, ..., 2048 bytes.
+const MIN_CLASS_SHIFT: usize = 4;
+const NCLASS: usize = 8;
+
+struct KHeap {
+    lock: Spinlock,
+    // Free objects of each size class, linked through their first word.
+    free_list: [Cell<*mut u8>; NCLASS],
+}
+
+unsafe impl Sync for KHeap {}
+
+#[global_allocator]
+static KHEAP: KHeap = KHeap {
+    lock: Spinlock::new("kheap"),
+    free_list: [const { Cell::new(null_mut()) }; NCLASS],
+};
+
+unsafe impl GlobalAlloc for KHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class(layout) {
+            Some(class) => self.lock.with_lock(|| {
+                if self.free_list[class].get().is_null() && !self.grow(class) {
+                    return null_mut();
+                }
+                let r = self.free_list[class].get();
+                self.free_list[class].set(unsafe { *(r as *mut *mut u8) });
+                r
+            }),
+            None => buddy::alloc_block(page_order(layout)),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(layout) {
+            Some(class) => self.lock.with_lock(|| unsafe {
+                (ptr as *mut *mut u8).write(self.free_list[class].get());
+                self.free_list[class].set(ptr);
+            }),
+            None => buddy::free_block(ptr, page_order(layout)),
+        }
+    }
+}
+
+impl KHeap {
+    // Carve a fresh page into objects of `class` and put them on its free
+    // list. Returns false if out of memory. Caller must hold the lock.
+    // Slab pages are never handed back to `kalloc`.
+    fn grow(&self, class: usize) -> bool {
+        let page = match kalloc() {
+            Some(page) => page,
+            None => return false,
+        };
+        let base = page.get_addr();
+        // The heap owns the page from now on.
+        core::mem::forget(page);
+        let size = 1 << (class + MIN_CLASS_SHIFT);
+        for obj in (base..base + CurrentArch::page_size()).step_by(size).rev() {
+            unsafe { (obj as *mut *mut u8).write(self.free_list[class].get()) };
+            self.free_list[class].set(obj as *mut u8);
+        }
+        true
+    }
+}
+
+// Slab class serving `layout`, or `None` if it needs whole pages. Objects are
+// naturally aligned to their size, so rounding up to the alignment suffices.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_CLASS_SHIFT);
+    let class = size.next_power_of_two().trailing_zeros() as usize - MIN_CLASS_SHIFT;
+    if class < NCLASS { Some(class) } else { None }
+}
+
+// Buddy order of the page run serving a large `layout`.
+fn page_order(layout: Layout) -> usize {
+    let size = layout.size().max(layout.align());
+    let pages = size.div_ceil(CurrentArch::page_size());
+    pages.next_power_of_two().trailing_zeros() as usize
+}
+
+#[alloc_error_handler]
+fn kheap_oom(layout: Layout) -> ! {
+    panic!(
+        "kheap: out of memory allocating {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+}
@@ -1,14 +1,31 @@
 use crate::arch::{Arch, CurrentArch};
+use crate::drivers::virtio;
 use crate::kalloc;
+use crate::memlayout;
+use crate::net;
 use crate::plic;
 use crate::print;
 use crate::trap;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-/// start::start() jumps here in supervisor mode on stack0 on all CPUs.
-pub fn kmain() -> ! {
-    if CurrentArch::cpuid() == 0 {
+// Set by the cpu that boots the kernel.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// start::start() jumps here in supervisor mode on stack0 on all CPUs, with
+/// the address of the device tree blob. The first CPU to get here boots the
+/// kernel. That need not be CPU 0 under OpenSBI.
+pub fn kmain(dtb: usize) -> ! {
+    if !STARTED.swap(true, Ordering::AcqRel) {
+        // Find RAM, harts and devices. Must come first, the console needs
+        // the uart's address.
+        memlayout::init(dtb);
         crate::console::consoleinit();
         print::println!("\nrxv6 kernel booting\n");
+        print::println!(
+            "{} harts, {} MB of RAM\n",
+            memlayout::ncpu(),
+            (memlayout::phystop() - memlayout::KERNBASE) >> 20
+        );
         // Physical page allocator.
         kalloc::kinit();
         // Install kernel trap vector.
@@ -17,9 +34,19 @@ pub fn kmain() -> ! {
         plic::plicinit();
         // Ask PLIC for device interrupts.
         plic::plicinithart();
+        // Find and start virtio devices.
+        virtio::virtio_probe();
+        // Wake up the other CPUs if the firmware keeps them stopped.
+        for cpu in 0..memlayout::ncpu() {
+            if cpu != CurrentArch::cpuid() {
+                CurrentArch::start_cpu(cpu, dtb);
+            }
+        }
+        // There is no scheduler yet. Meanwhile this cpu runs the network
+        // stack, so the guest answers pings.
+        net::net_serve()
     } else {
         // Implement other CPU initialization here.
         CurrentArch::halt();
     }
-    loop {}
 }
@@ -3,10 +3,11 @@
 /// Qemu -machine virt is set up like this, based on qemu's hw/riscv/virt.c:
 ///
 /// 0x00001000 -- boot ROM, provided by qemu
+/// 0x00100000 -- test finisher, used for poweroff and reboot
 /// 0x02000000 -- CLINT
 /// 0x0C000000 -- PLIC
 /// 0x10000000 -- uart0
-/// 0x10001000 -- virtio disk
+/// 0x10001000 -- virtio mmio transports, 0x1000 apart
 /// 0x80000000 -- qemu's boot ROM loads the kernel here,
 ///             then jumps here.
 /// Unused RAM after 0x80000000.
@@ -15,49 +16,239 @@
 /// 0x80000000 -- asm/entry.S
 /// Kernel text and data
 /// end -- start of kernel page allocation area
-/// PHYSTOP -- end RAM used by the kernel
+/// phystop() -- end RAM used by the kernel
+///
+/// The addresses above are qemu's defaults. `init()` replaces them with what
+/// the device tree passed in by the boot ROM says, so the amount of RAM, the
+/// number of harts and the devices follow qemu's -m, -smp and -device options.
+use crate::arch::{Arch, CurrentArch};
+use crate::fdt::Fdt;
+use crate::param;
+
+/// Qemu's sifive test device. Writing to it powers off or resets the machine.
+pub const VIRT_TEST: usize = 0x100000;
 
 /// Qemu puts UART registers here in physical memory.
-pub const UART0: usize = 0x10000000;
-pub const UART0_IRQ: u32 = 10;
+pub fn uart0() -> usize {
+    unsafe { (*layout()).uart0 }
+}
+
+pub fn uart0_irq() -> u32 {
+    unsafe { (*layout()).uart0_irq }
+}
 
-/// Virtio mmio interface.
-pub const VIRTIO0: usize = 0x10001000;
-pub const VIRTIO0_IRQ: u32 = 1;
+/// Returns false if the device tree has no uart. The console then goes to
+/// the firmware, if there is one.
+pub fn has_uart() -> bool {
+    unsafe { (*layout()).uart0 != 0 }
+}
+
+/// Most virtio mmio transports we keep track of. Qemu's virt machine has 8.
+pub const NVIRTIO: usize = 8;
+
+/// Returns the number of virtio mmio transports.
+pub fn nvirtio() -> usize {
+    unsafe { (*layout()).nvirtio }
+}
+
+/// Returns the base address of virtio transport `slot`. Transports are
+/// numbered in address order, which matches qemu's virtio-mmio-bus.N.
+pub fn virtio_mmio(slot: usize) -> usize {
+    unsafe { (*layout()).virtio[slot].0 }
+}
+
+/// Returns the irq of virtio transport `slot`.
+pub fn virtio_irq(slot: usize) -> u32 {
+    unsafe { (*layout()).virtio[slot].1 }
+}
+
+/// Returns the virtio transport raising `irq`, if any.
+pub fn virtio_slot(irq: u32) -> Option<usize> {
+    (0..nvirtio()).find(|&slot| virtio_irq(slot) == irq)
+}
+
+/// Qemu puts the core-local interruptor (CLINT) here.
+pub fn clint() -> usize {
+    unsafe { (*layout()).clint }
+}
 
 /// Qemu puts platform-level interrupt controller (PLIC) here.
-pub const PLIC: usize = 0x0c000000;
-pub const PLIC_PRIORITY: usize = PLIC + 0x0;
-pub const PLIC_PENDING: usize = PLIC + 0x1000;
+pub fn plic() -> usize {
+    unsafe { (*layout()).plic }
+}
 
 /// Returns address of the plic priority register for the device irq
-/// (e.g. `UART0_IRQ`).
+/// (e.g. `uart0_irq()`).
 pub fn plic_device_priority(irq: u32) -> usize {
-    PLIC + (irq as usize) * 4
+    plic() + (irq as usize) * 4
 }
 
 /// Returns address of the plic enable register for supervisor mode for
 /// `hart` (odd contexts).
 #[inline(always)]
 pub fn plic_senable(hart: usize) -> usize {
-    PLIC + 0x2080 + (hart) * 0x100
+    plic() + 0x2080 + (hart) * 0x100
 }
 
 /// Returns address of the plic priority register for supervisor mode for
 /// `hart` (odd contexts).
 #[inline(always)]
 pub fn plic_spriority(hart: usize) -> usize {
-    PLIC + 0x201000 + (hart) * 0x2000
+    plic() + 0x201000 + (hart) * 0x2000
 }
 
 /// Returns address of the plic claim register for supervisor mode for
 /// `hart` (odd contexts).
 #[inline(always)]
 pub fn plic_sclaim(hart: usize) -> usize {
-    PLIC + 0x201004 + (hart) * 0x2000
+    plic() + 0x201004 + (hart) * 0x2000
 }
 
 // The kernel expects there to be RAM for use by the kernel and user pages from
-// physical address 0x80000000 to `PHYSTOP`.
+// physical address 0x80000000 to `phystop()`.
 pub const KERNBASE: usize = 0x80000000;
-pub const PHYSTOP: usize = KERNBASE + 128 * 1024 * 1024; // 128 MB
+/// The kernel ignores RAM beyond this.
+pub const PHYSTOP_MAX: usize = KERNBASE + param::MAXRAM;
+/// Most physical pages between `KERNBASE` and `phystop()`. Uses the riscv
+/// page size since `CurrentArch::page_size()` is not const.
+pub const NPAGES: usize = (PHYSTOP_MAX - KERNBASE) / 4096;
+
+/// Index of the physical page containing `pa`, counted from `KERNBASE`.
+#[inline(always)]
+pub fn page_index(pa: usize) -> usize {
+    (pa - KERNBASE) / CurrentArch::page_size()
+}
+
+/// Physical address of the page with index `idx`.
+#[inline(always)]
+pub fn page_addr(idx: usize) -> usize {
+    KERNBASE + idx * CurrentArch::page_size()
+}
+
+/// Returns the end of RAM used by the kernel.
+pub fn phystop() -> usize {
+    unsafe { (*layout()).phystop }
+}
+
+/// Returns the number of harts, at most `param::NCPU`.
+pub fn ncpu() -> usize {
+    unsafe { (*layout()).ncpu }
+}
+
+/// Fill in the layout from the device tree blob at `dtb`. Anything the tree
+/// doesn't mention, or all of it if there is no valid tree, keeps qemu's
+/// default. Must run on one hart before anything else uses the layout.
+pub fn init(dtb: usize) {
+    let Some(fdt) = Fdt::new(dtb) else {
+        return;
+    };
+    let layout = &raw mut LAYOUT;
+    let mut ncpu = 0;
+    let mut nvirtio = 0;
+    let mut virtio = [(0, 0); NVIRTIO];
+    let mut ram_end = None;
+    let mut uart = None;
+
+    fdt.for_each_node(|node| {
+        let reg = node.reg().next();
+        match node.device_type() {
+            Some("memory") => {
+                // Use the bank the kernel was loaded into.
+                for (base, size) in node.reg() {
+                    if base <= KERNBASE && KERNBASE < base + size {
+                        ram_end = Some(base + size);
+                    }
+                }
+                return;
+            }
+            Some("cpu") => {
+                ncpu += 1;
+                return;
+            }
+            _ => {}
+        }
+        let Some((base, _)) = reg else {
+            return;
+        };
+        if node.is_compatible("ns16550a") {
+            uart = Some((base, node.irq().unwrap_or(unsafe { (*layout).uart0_irq })));
+        } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+            unsafe { (*layout).plic = base };
+        } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+            unsafe { (*layout).clint = base };
+        } else if node.is_compatible("virtio,mmio")
+            && nvirtio < NVIRTIO
+            && let Some(irq) = node.irq()
+        {
+            virtio[nvirtio] = (base, irq);
+            nvirtio += 1;
+        }
+    });
+
+    unsafe {
+        match uart {
+            Some((base, irq)) => {
+                (*layout).uart0 = base;
+                (*layout).uart0_irq = irq;
+            }
+            None => (*layout).uart0 = 0,
+        }
+        if ncpu > 0 {
+            (*layout).ncpu = ncpu.min(param::NCPU);
+        }
+        if nvirtio > 0 {
+            // Qemu lists the transports from the highest address down.
+            virtio[..nvirtio].sort_unstable();
+            (*layout).virtio = virtio;
+            (*layout).nvirtio = nvirtio;
+        }
+        if let Some(end) = ram_end {
+            let mut phystop = end.min(PHYSTOP_MAX);
+            // Qemu copies the tree to the top of RAM. Keep it out of the page
+            // allocator so it stays valid.
+            let (fdt_start, _) = fdt.range();
+            if KERNBASE <= fdt_start && fdt_start < phystop {
+                phystop = fdt_start & !0xfff;
+            }
+            (*layout).phystop = phystop;
+        }
+    }
+}
+
+// Memory and devices of the machine, see `init()`.
+struct Layout {
+    phystop: usize,
+    ncpu: usize,
+    // 0 if there is no uart.
+    uart0: usize,
+    uart0_irq: u32,
+    clint: usize,
+    plic: usize,
+    // Base address and irq of each virtio transport.
+    virtio: [(usize, u32); NVIRTIO],
+    nvirtio: usize,
+}
+
+// Qemu's virt machine with -m 128M, used if there is no device tree.
+static mut LAYOUT: Layout = Layout {
+    phystop: KERNBASE + 128 * 1024 * 1024,
+    ncpu: param::NCPU,
+    uart0: 0x10000000,
+    uart0_irq: 10,
+    clint: 0x2000000,
+    plic: 0x0c000000,
+    virtio: {
+        let mut virtio = [(0, 0); NVIRTIO];
+        let mut slot = 0;
+        while slot < NVIRTIO {
+            virtio[slot] = (0x10001000 + slot * 0x1000, 1 + slot as u32);
+            slot += 1;
+        }
+        virtio
+    },
+    nvirtio: NVIRTIO,
+};
+
+fn layout() -> *const Layout {
+    &raw const LAYOUT
+}
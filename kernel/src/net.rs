@@ -0,0 +1,134 @@
+/// Kernel network stack: ethernet, ARP, IPv4, ICMP echo and UDP on top of the
+/// virtio-net driver.
+///
+/// Addresses match qemu's user networking (slirp), where the guest is
+/// 10.0.2.15 and the host is reachable through the gateway 10.0.2.2.
+///
+/// There are no socket syscalls yet. Until there are, the boot cpu runs
+/// `net_serve()`, which answers pings and echoes UDP datagrams sent to
+/// `ECHO_PORT`.
+mod arp;
+mod ip;
+pub mod udp;
+
+use crate::arch::{Arch, CurrentArch};
+use crate::drivers::virtio::net::{self as nic, MAX_FRAME};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+
+/// An IPv4 address in host byte order.
+pub type Ipv4Addr = u32;
+
+/// Builds an `Ipv4Addr` from its dotted-quad parts.
+pub const fn ipv4(a: u8, b: u8, c: u8, d: u8) -> Ipv4Addr {
+    u32::from_be_bytes([a, b, c, d])
+}
+
+/// Our address.
+pub const LOCAL_IP: Ipv4Addr = ipv4(10, 0, 2, 15);
+/// Where packets for other subnets go.
+pub const GATEWAY_IP: Ipv4Addr = ipv4(10, 0, 2, 2);
+pub const NETMASK: Ipv4Addr = ipv4(255, 255, 255, 0);
+/// UDP port of the echo service. `just run-net` forwards the same host port
+/// to it.
+pub const ECHO_PORT: u16 = 26999;
+
+type MacAddr = [u8; 6];
+
+const BROADCAST_MAC: MacAddr = [0xff; 6];
+
+// Ethernet header: destination, source, ethertype.
+const ETH_HDR_SIZE: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Run the network stack on this cpu forever: answer ARP requests and pings,
+/// and send every datagram arriving on `ECHO_PORT` back to its sender. Halts
+/// if there is no network device.
+pub fn net_serve() -> ! {
+    if !nic::virtio_net_ready() {
+        CurrentArch::halt();
+    }
+    let Some(sock) = udp::udp_socket() else {
+        panic!("net_serve: socket");
+    };
+    if !udp::udp_bind(sock, ECHO_PORT) {
+        panic!("net_serve: bind");
+    }
+    let mut buf = vec![0u8; udp::UDP_MAX_PAYLOAD];
+    loop {
+        // Polls the device, which also handles ARP and ICMP.
+        match udp::udp_recvfrom(sock, &mut buf) {
+            Some((n, ip, port)) => {
+                udp::udp_sendto(sock, &buf[..n], ip, port);
+            }
+            None => spin_loop(),
+        }
+    }
+}
+
+/// Drain every frame the NIC has received and hand it to the protocol it
+/// belongs to. Called by the socket functions before they look for data.
+///
+/// Device interrupts only move frames into the driver's pool, the stack runs
+/// here. Frames arriving while nobody polls are dropped once the pool is full.
+pub fn net_poll() {
+    // Kernel stacks are small, keep the frame on the heap.
+    let mut frame = vec![0u8; MAX_FRAME];
+    while let Some(n) = nic::virtio_net_recv(&mut frame) {
+        net_rx(&frame[..n]);
+    }
+    arp::arp_timer();
+}
+
+// Dispatch one received ethernet frame.
+fn net_rx(frame: &[u8]) {
+    if frame.len() < ETH_HDR_SIZE {
+        return;
+    }
+    let payload = &frame[ETH_HDR_SIZE..];
+    match get_u16(frame, 12) {
+        ETHERTYPE_ARP => arp::arp_rx(payload),
+        ETHERTYPE_IPV4 => ip::ip_rx(payload),
+        _ => {}
+    }
+}
+
+// Prepend an ethernet header to `payload` and transmit it.
+fn eth_tx(dst: MacAddr, ethertype: u16, payload: &[u8]) -> bool {
+    let mut frame = Vec::with_capacity(ETH_HDR_SIZE + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&nic::virtio_net_mac());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    nic::virtio_net_send(&frame)
+}
+
+// Internet checksum (RFC 1071) of `data`, continuing from the partial sum
+// `sum`.
+fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    let (words, rest) = data.as_chunks::<2>();
+    for w in words {
+        sum += u16::from_be_bytes(*w) as u32;
+    }
+    if let [last] = rest {
+        sum += (*last as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Read a big-endian u16 at `off`.
+#[inline(always)]
+fn get_u16(data: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([data[off], data[off + 1]])
+}
+
+// Read a big-endian u32 at `off`.
+#[inline(always)]
+fn get_u32(data: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(data[off..off + 4].try_into().unwrap())
+}
@@ -0,0 +1,265 @@
+/// Address Resolution Protocol (RFC 826) for IPv4 over ethernet.
+///
+/// Keeps a small cache of neighbours. IP packets for a neighbour that isn't
+/// cached yet wait in `pending` until its reply arrives. Unanswered requests
+/// are resent by `arp_timer()`, which gives up on the neighbour after
+/// `ARP_TRIES` requests and drops its packets.
+use super::{
+    BROADCAST_MAC, ETHERTYPE_ARP, ETHERTYPE_IPV4, Ipv4Addr, LOCAL_IP, MacAddr, eth_tx, get_u16,
+    get_u32,
+};
+use crate::arch::{Arch, CurrentArch};
+use crate::drivers::virtio::net as nic;
+use crate::spinlock::Spinlock;
+use alloc::vec::Vec;
+
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+const ARP_PKT_SIZE: usize = 28;
+
+// Number of cached neighbours. The oldest entry is evicted first.
+const CACHE_SIZE: usize = 16;
+// IP packets kept while waiting for replies. Further packets are dropped.
+const MAX_PENDING: usize = 16;
+// Resend a request after this many `time()` ticks, a second on qemu's 10 MHz
+// timer.
+const ARP_TIMEOUT: usize = 10_000_000;
+// Requests sent for a neighbour before giving up on it.
+const ARP_TRIES: usize = 3;
+
+/// Transmit the IPv4 `packet` to the neighbour `next_hop`, resolving its
+/// hardware address first if needed.
+pub fn arp_send_ip(next_hop: Ipv4Addr, packet: Vec<u8>) -> bool {
+    let arp = &raw mut ARP;
+    let tk = unsafe { (*arp).lock.acquire() };
+    let mac = unsafe { (*arp).lookup(next_hop) };
+    // Only the first packet waiting for `next_hop` sends a request, the rest
+    // wait for the same reply. `arp_timer()` takes care of retries.
+    let (queued, first) = match mac {
+        Some(_) => (false, false),
+        None => unsafe {
+            let queued = (*arp).queue(next_hop, packet.clone());
+            (queued, queued && (*arp).start_request(next_hop))
+        },
+    };
+    unsafe { (*arp).lock.release(tk) };
+
+    match mac {
+        Some(mac) => eth_tx(mac, ETHERTYPE_IPV4, &packet),
+        None => {
+            if first {
+                arp_request(next_hop);
+            }
+            queued
+        }
+    }
+}
+
+/// Resend requests that went unanswered for `ARP_TIMEOUT` or could not be
+/// sent, and drop the packets for neighbours that didn't answer `ARP_TRIES`
+/// requests. Called from `net_poll()`.
+pub fn arp_timer() {
+    let arp = &raw mut ARP;
+    let tk = unsafe { (*arp).lock.acquire() };
+    let due = unsafe { (*arp).due_requests(CurrentArch::time()) };
+    unsafe { (*arp).lock.release(tk) };
+
+    for ip in due {
+        arp_request(ip);
+    }
+}
+
+/// Handle a received ARP packet following the algorithm in RFC 826: refresh
+/// the sender if it is cached, cache it if the packet is for us, answer
+/// requests for our address and flush packets that were waiting for the
+/// sender.
+pub fn arp_rx(pkt: &[u8]) {
+    if pkt.len() < ARP_PKT_SIZE
+        || get_u16(pkt, 0) != ARP_HTYPE_ETHERNET
+        || get_u16(pkt, 2) != ETHERTYPE_IPV4
+        || pkt[4] != 6
+        || pkt[5] != 4
+    {
+        return;
+    }
+    let op = get_u16(pkt, 6);
+    let sha: MacAddr = pkt[8..14].try_into().unwrap();
+    let spa = get_u32(pkt, 14);
+    let tpa = get_u32(pkt, 24);
+    // An ARP probe from a host without an address yet.
+    if spa == 0 {
+        return;
+    }
+
+    let arp = &raw mut ARP;
+    let tk = unsafe { (*arp).lock.acquire() };
+    let ready = unsafe {
+        // Refresh the sender if we know it. Only hosts talking to us are
+        // added, so broadcasts between others don't evict our neighbours.
+        let merged = (*arp).update(spa, sha);
+        if tpa != LOCAL_IP && !merged {
+            Vec::new()
+        } else {
+            if !merged {
+                (*arp).insert(spa, sha);
+            }
+            (*arp).take_pending(spa)
+        }
+    };
+    unsafe { (*arp).lock.release(tk) };
+
+    for packet in ready {
+        eth_tx(sha, ETHERTYPE_IPV4, &packet);
+    }
+    if op == ARP_OP_REQUEST && tpa == LOCAL_IP {
+        arp_tx(ARP_OP_REPLY, sha, spa);
+    }
+}
+
+// Broadcast a request for the address of `ip`. If the device doesn't take
+// it, the next `arp_timer()` tries again.
+fn arp_request(ip: Ipv4Addr) {
+    if !arp_tx(ARP_OP_REQUEST, BROADCAST_MAC, ip) {
+        return;
+    }
+    let arp = &raw mut ARP;
+    let tk = unsafe { (*arp).lock.acquire() };
+    unsafe { (*arp).request_sent(ip, CurrentArch::time()) };
+    unsafe { (*arp).lock.release(tk) };
+}
+
+// Send an ARP packet of type `op` to `tha`/`tpa`.
+fn arp_tx(op: u16, tha: MacAddr, tpa: Ipv4Addr) -> bool {
+    let mut pkt = Vec::with_capacity(ARP_PKT_SIZE);
+    pkt.extend_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+    pkt.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    // Hardware and protocol address lengths.
+    pkt.extend_from_slice(&[6, 4]);
+    pkt.extend_from_slice(&op.to_be_bytes());
+    pkt.extend_from_slice(&nic::virtio_net_mac());
+    pkt.extend_from_slice(&LOCAL_IP.to_be_bytes());
+    // Requests leave the target hardware address zeroed.
+    let tha_field = if op == ARP_OP_REQUEST { [0; 6] } else { tha };
+    pkt.extend_from_slice(&tha_field);
+    pkt.extend_from_slice(&tpa.to_be_bytes());
+    eth_tx(tha, ETHERTYPE_ARP, &pkt)
+}
+
+struct Arp {
+    // Known neighbours, oldest first.
+    cache: Vec<(Ipv4Addr, MacAddr)>,
+    // Packets waiting for the address of their next hop.
+    pending: Vec<(Ipv4Addr, Vec<u8>)>,
+    // Neighbours being resolved, one per next hop in `pending`.
+    requests: Vec<Request>,
+    lock: Spinlock,
+}
+
+// Resolution state of one neighbour.
+struct Request {
+    ip: Ipv4Addr,
+    // `time()` the last request was sent, `None` if none has gone out yet.
+    sent: Option<usize>,
+    // Requests sent so far.
+    tries: usize,
+}
+
+unsafe impl Sync for Arp {}
+
+static mut ARP: Arp = Arp {
+    cache: Vec::new(),
+    pending: Vec::new(),
+    requests: Vec::new(),
+    lock: Spinlock::new("arp"),
+};
+
+impl Arp {
+    fn lookup(&self, ip: Ipv4Addr) -> Option<MacAddr> {
+        self.cache.iter().find(|e| e.0 == ip).map(|e| e.1)
+    }
+
+    // Replace the address of `ip` if it is cached. Returns false if it isn't.
+    fn update(&mut self, ip: Ipv4Addr, mac: MacAddr) -> bool {
+        match self.cache.iter_mut().find(|e| e.0 == ip) {
+            Some(e) => {
+                e.1 = mac;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Cache `ip`, which must not be cached yet.
+    fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        if self.cache.len() == CACHE_SIZE {
+            self.cache.remove(0);
+        }
+        self.cache.push((ip, mac));
+    }
+
+    // Start resolving `ip`. Returns false if it is already being resolved.
+    fn start_request(&mut self, ip: Ipv4Addr) -> bool {
+        if self.requests.iter().any(|r| r.ip == ip) {
+            return false;
+        }
+        self.requests.push(Request {
+            ip,
+            sent: None,
+            tries: 0,
+        });
+        true
+    }
+
+    // Note that a request for `ip` went out at `now`.
+    fn request_sent(&mut self, ip: Ipv4Addr, now: usize) {
+        if let Some(r) = self.requests.iter_mut().find(|r| r.ip == ip) {
+            r.sent = Some(now);
+            r.tries += 1;
+        }
+    }
+
+    // Returns the neighbours whose request should be sent again at `now`, and
+    // gives up on those that had enough tries.
+    fn due_requests(&mut self, now: usize) -> Vec<Ipv4Addr> {
+        let mut due = Vec::new();
+        let mut failed = Vec::new();
+        self.requests.retain(|r| {
+            if r.sent.is_some_and(|t| now.wrapping_sub(t) < ARP_TIMEOUT) {
+                true
+            } else if r.tries >= ARP_TRIES {
+                failed.push(r.ip);
+                false
+            } else {
+                due.push(r.ip);
+                true
+            }
+        });
+        self.pending.retain(|(dst, _)| !failed.contains(dst));
+        due
+    }
+
+    // Keep `packet` until `ip` is resolved. Returns false if it was dropped.
+    fn queue(&mut self, ip: Ipv4Addr, packet: Vec<u8>) -> bool {
+        if self.pending.len() == MAX_PENDING {
+            return false;
+        }
+        self.pending.push((ip, packet));
+        true
+    }
+
+    // Remove and return the packets waiting for `ip`, which is now resolved.
+    fn take_pending(&mut self, ip: Ipv4Addr) -> Vec<Vec<u8>> {
+        self.requests.retain(|r| r.ip != ip);
+        let mut ready = Vec::new();
+        self.pending.retain_mut(|(dst, packet)| {
+            if *dst == ip {
+                ready.push(core::mem::take(packet));
+                false
+            } else {
+                true
+            }
+        });
+        ready
+    }
+}
@@ -0,0 +1,107 @@
+/// IPv4 (RFC 791) and ICMP echo (RFC 792).
+///
+/// Options and fragments are not supported: received fragments are dropped
+/// and we never send packets larger than the link MTU.
+use super::arp::arp_send_ip;
+use super::udp::udp_rx;
+use super::{ETH_HDR_SIZE, GATEWAY_IP, Ipv4Addr, LOCAL_IP, NETMASK, checksum, get_u16, get_u32};
+use crate::drivers::virtio::net::MAX_FRAME;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const IP_PROTO_ICMP: u8 = 1;
+pub const IP_PROTO_UDP: u8 = 17;
+
+pub const IP_HDR_SIZE: usize = 20;
+/// Largest payload that fits in one frame.
+pub const IP_MAX_PAYLOAD: usize = MAX_FRAME - ETH_HDR_SIZE - IP_HDR_SIZE;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HDR_SIZE: usize = 8;
+
+// "More fragments" flag and fragment offset mask in the flags/offset field.
+const IP_MF: u16 = 0x2000;
+const IP_OFFSET_MASK: u16 = 0x1fff;
+
+const IP_TTL: u8 = 64;
+
+// Identification field of the next packet we send.
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Send `payload` to `dst` as one IPv4 packet of protocol `proto`.
+pub fn ip_tx(dst: Ipv4Addr, proto: u8, payload: &[u8]) -> bool {
+    if payload.len() > IP_MAX_PAYLOAD {
+        return false;
+    }
+    let total_len = (IP_HDR_SIZE + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(total_len as usize);
+    // Version 4, header length 5 words, default type of service.
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    // No flags, offset 0.
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&[IP_TTL, proto]);
+    // Checksum, filled in below.
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&LOCAL_IP.to_be_bytes());
+    packet.extend_from_slice(&dst.to_be_bytes());
+    let sum = checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    // Packets for other subnets go through the gateway.
+    let next_hop = if (dst & NETMASK) == (LOCAL_IP & NETMASK) {
+        dst
+    } else {
+        GATEWAY_IP
+    };
+    arp_send_ip(next_hop, packet)
+}
+
+/// Handle a received IPv4 packet addressed to us.
+pub fn ip_rx(packet: &[u8]) {
+    if packet.len() < IP_HDR_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let hdr_len = ((packet[0] & 0xf) as usize) * 4;
+    let total_len = get_u16(packet, 2) as usize;
+    if hdr_len < IP_HDR_SIZE
+        || total_len < hdr_len
+        || total_len > packet.len()
+        || checksum(&packet[..hdr_len], 0) != 0
+    {
+        return;
+    }
+    let frag = get_u16(packet, 6);
+    if frag & (IP_MF | IP_OFFSET_MASK) != 0 {
+        return;
+    }
+    let src = get_u32(packet, 12);
+    let dst = get_u32(packet, 16);
+    if dst != LOCAL_IP {
+        return;
+    }
+    // Ethernet may pad short frames, so trust the length field.
+    let payload = &packet[hdr_len..total_len];
+    match packet[9] {
+        IP_PROTO_ICMP => icmp_rx(src, payload),
+        IP_PROTO_UDP => udp_rx(src, payload),
+        _ => {}
+    }
+}
+
+// Answer echo requests, ignore everything else.
+fn icmp_rx(src: Ipv4Addr, msg: &[u8]) {
+    if msg.len() < ICMP_HDR_SIZE || msg[0] != ICMP_ECHO_REQUEST || checksum(msg, 0) != 0 {
+        return;
+    }
+    // The reply echoes identifier, sequence number and data.
+    let mut reply = msg.to_vec();
+    reply[0] = ICMP_ECHO_REPLY;
+    reply[2..4].fill(0);
+    let sum = checksum(&reply, 0);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    ip_tx(src, IP_PROTO_ICMP, &reply);
+}
@@ -0,0 +1,180 @@
+/// UDP (RFC 768) and the datagram socket interface.
+///
+/// Sockets are small integers indexing `SOCKETS`. Received datagrams queue on
+/// the socket bound to their destination port until `udp_recvfrom()` takes
+/// them. None of the calls block.
+use super::ip::{IP_MAX_PAYLOAD, IP_PROTO_UDP, ip_tx};
+use super::{Ipv4Addr, LOCAL_IP, checksum, get_u16, net_poll};
+use crate::spinlock::Spinlock;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+const UDP_HDR_SIZE: usize = 8;
+/// Largest datagram payload we can send.
+pub const UDP_MAX_PAYLOAD: usize = IP_MAX_PAYLOAD - UDP_HDR_SIZE;
+
+/// Maximum number of open sockets.
+pub const NSOCKET: usize = 16;
+// Datagrams queued per socket. Further datagrams are dropped.
+const MAX_QUEUED: usize = 16;
+// Ports handed to sockets that send before binding.
+const EPHEMERAL_FIRST: u16 = 49152;
+
+/// Allocate a new, unbound socket. Returns `None` if all sockets are in use.
+pub fn udp_socket() -> Option<usize> {
+    with_sockets(|socks| {
+        let sock = socks.iter().position(|s| s.is_none())?;
+        socks[sock] = Some(Socket {
+            port: 0,
+            queue: VecDeque::new(),
+        });
+        Some(sock)
+    })
+}
+
+/// Bind `sock` to local `port`. Fails if the socket is already bound or the
+/// port is taken.
+pub fn udp_bind(sock: usize, port: u16) -> bool {
+    with_sockets(|socks| {
+        if port == 0 || socks.iter().flatten().any(|s| s.port == port) {
+            return false;
+        }
+        match socks.get_mut(sock) {
+            Some(Some(s)) if s.port == 0 => {
+                s.port = port;
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Send `data` from `sock` to `dst_ip:dst_port`. An unbound socket is first
+/// bound to a free ephemeral port.
+pub fn udp_sendto(sock: usize, data: &[u8], dst_ip: Ipv4Addr, dst_port: u16) -> bool {
+    if data.len() > UDP_MAX_PAYLOAD {
+        return false;
+    }
+    let src_port = with_sockets(|socks| {
+        if !matches!(socks.get(sock), Some(Some(_))) {
+            return None;
+        }
+        if socks[sock].as_ref().unwrap().port == 0 {
+            let port = (EPHEMERAL_FIRST..=u16::MAX)
+                .find(|&p| !socks.iter().flatten().any(|s| s.port == p))?;
+            socks[sock].as_mut().unwrap().port = port;
+        }
+        socks[sock].as_ref().map(|s| s.port)
+    });
+    let src_port = match src_port {
+        Some(port) => port,
+        None => return false,
+    };
+
+    let len = (UDP_HDR_SIZE + data.len()) as u16;
+    let mut seg = Vec::with_capacity(len as usize);
+    seg.extend_from_slice(&src_port.to_be_bytes());
+    seg.extend_from_slice(&dst_port.to_be_bytes());
+    seg.extend_from_slice(&len.to_be_bytes());
+    // Checksum, filled in below.
+    seg.extend_from_slice(&[0, 0]);
+    seg.extend_from_slice(data);
+    let mut sum = checksum(&seg, pseudo_sum(LOCAL_IP, dst_ip, len));
+    // Zero means "no checksum", send all ones instead.
+    if sum == 0 {
+        sum = 0xffff;
+    }
+    seg[6..8].copy_from_slice(&sum.to_be_bytes());
+    ip_tx(dst_ip, IP_PROTO_UDP, &seg)
+}
+
+/// Copy the oldest datagram queued on `sock` into `buf`. Returns its length
+/// and sender, or `None` if nothing has arrived. Datagrams longer than `buf`
+/// are truncated.
+pub fn udp_recvfrom(sock: usize, buf: &mut [u8]) -> Option<(usize, Ipv4Addr, u16)> {
+    // Pick up anything the NIC has received.
+    net_poll();
+    let dgram = with_sockets(|socks| socks.get_mut(sock)?.as_mut()?.queue.pop_front())?;
+    let n = dgram.data.len().min(buf.len());
+    buf[..n].copy_from_slice(&dgram.data[..n]);
+    Some((n, dgram.src_ip, dgram.src_port))
+}
+
+/// Close `sock`, dropping any queued datagrams.
+pub fn udp_close(sock: usize) {
+    with_sockets(|socks| {
+        if let Some(s) = socks.get_mut(sock) {
+            *s = None;
+        }
+    });
+}
+
+/// Handle a received UDP datagram from `src`.
+pub fn udp_rx(src: Ipv4Addr, seg: &[u8]) {
+    if seg.len() < UDP_HDR_SIZE {
+        return;
+    }
+    let len = get_u16(seg, 4) as usize;
+    if len < UDP_HDR_SIZE || len > seg.len() {
+        return;
+    }
+    let seg = &seg[..len];
+    // A zero checksum means the sender did not compute one.
+    if get_u16(seg, 6) != 0 && checksum(seg, pseudo_sum(src, LOCAL_IP, len as u16)) != 0 {
+        return;
+    }
+    let src_port = get_u16(seg, 0);
+    let dst_port = get_u16(seg, 2);
+    with_sockets(|socks| {
+        let sock = socks.iter_mut().flatten().find(|s| s.port == dst_port);
+        if let Some(sock) = sock
+            && sock.queue.len() < MAX_QUEUED
+        {
+            sock.queue.push_back(Datagram {
+                src_ip: src,
+                src_port,
+                data: seg[UDP_HDR_SIZE..].to_vec(),
+            });
+        }
+    });
+}
+
+// Partial checksum of the IPv4 pseudo header for a UDP segment of `len`
+// bytes.
+fn pseudo_sum(src: Ipv4Addr, dst: Ipv4Addr, len: u16) -> u32 {
+    (src >> 16) + (src & 0xffff) + (dst >> 16) + (dst & 0xffff) + IP_PROTO_UDP as u32 + len as u32
+}
+
+struct Datagram {
+    src_ip: Ipv4Addr,
+    src_port: u16,
+    data: Vec<u8>,
+}
+
+struct Socket {
+    // Local port, 0 while unbound.
+    port: u16,
+    // Received datagrams, oldest first.
+    queue: VecDeque<Datagram>,
+}
+
+struct Sockets {
+    socks: [Option<Socket>; NSOCKET],
+    lock: Spinlock,
+}
+
+unsafe impl Sync for Sockets {}
+
+static mut SOCKETS: Sockets = Sockets {
+    socks: [const { None }; NSOCKET],
+    lock: Spinlock::new("udp"),
+};
+
+// Runs the given closure on the socket table with its lock held.
+fn with_sockets<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut [Option<Socket>; NSOCKET]) -> R,
+{
+    let sockets = &raw mut SOCKETS;
+    unsafe { (*sockets).lock.with_lock(|| f(&mut (*sockets).socks)) }
+}
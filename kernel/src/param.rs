@@ -1 +1,6 @@
+/// Maximum number of harts. Qemu may start more, the extra ones just spin.
 pub const NCPU: usize = 4;
+/// Most RAM the kernel will use, in bytes.
+pub const MAXRAM: usize = 1024 * 1024 * 1024;
+/// Maximum number of virtio disks.
+pub const NDISK: usize = 4;
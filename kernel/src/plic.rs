@@ -4,18 +4,24 @@ use crate::arch::{Arch, CurrentArch};
 use crate::memlayout;
 
 pub fn plicinit() {
-    write_reg(memlayout::plic_device_priority(memlayout::UART0_IRQ), 1);
-    write_reg(memlayout::plic_device_priority(memlayout::VIRTIO0_IRQ), 1);
+    write_reg(memlayout::plic_device_priority(memlayout::uart0_irq()), 1);
+    for slot in 0..memlayout::nvirtio() {
+        write_reg(
+            memlayout::plic_device_priority(memlayout::virtio_irq(slot)),
+            1,
+        );
+    }
 }
 
 pub fn plicinithart() {
     let hart = CurrentArch::cpuid();
 
-    // Set enable bits for this hart's S-mode for the uart and virtio disk.
-    write_reg(
-        memlayout::plic_senable(hart),
-        (1 << memlayout::UART0_IRQ) | (1 << memlayout::VIRTIO0_IRQ),
-    );
+    // Set enable bits for this hart's S-mode for the uart and all virtio
+    // transports.
+    enable(hart, memlayout::uart0_irq());
+    for slot in 0..memlayout::nvirtio() {
+        enable(hart, memlayout::virtio_irq(slot));
+    }
     // Set this hart's S-mode priority threshold to 0.
     write_reg(memlayout::plic_spriority(hart), 0);
 }
@@ -33,6 +39,12 @@ pub fn plic_complete(irq: u32) {
     write_reg(memlayout::plic_sclaim(hart), irq);
 }
 
+// Each enable register holds the bits of 32 irqs.
+fn enable(hart: usize, irq: u32) {
+    let r = memlayout::plic_senable(hart) + (irq as usize / 32) * 4;
+    write_reg(r, read_reg(r) | (1 << (irq % 32)));
+}
+
 fn write_reg(r: usize, val: u32) {
     unsafe { (r as *mut u32).write_volatile(val) };
 }
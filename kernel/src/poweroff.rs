@@ -0,0 +1,59 @@
+/// Shutdown and reboot through qemu's virt test finisher device, or through
+/// the firmware when booted under OpenSBI.
+#[cfg(feature = "sbi")]
+use crate::arch::sbi;
+use crate::arch::{Arch, CurrentArch};
+use crate::memlayout;
+
+// Commands understood by the test finisher, from qemu's
+// include/hw/misc/sifive_test.h
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Power off the machine. Qemu exits with status `code`; 0 means success.
+pub fn poweroff(code: u16) -> ! {
+    // The firmware only learns whether this is a failure, what qemu's exit
+    // status becomes is up to it.
+    #[cfg(feature = "sbi")]
+    sbi::system_reset(
+        sbi::RESET_SHUTDOWN,
+        if code == 0 {
+            sbi::RESET_REASON_NONE
+        } else {
+            sbi::RESET_REASON_FAILURE
+        },
+    );
+    // No firmware, or it can't shut down.
+    if code == 0 {
+        write_reg(FINISHER_PASS);
+    } else {
+        // The exit code is carried in the upper 16 bits.
+        write_reg(FINISHER_FAIL | ((code as u32) << 16));
+    }
+    // The write above should never return.
+    CurrentArch::halt()
+}
+
+/// Reset the machine. Qemu restarts from the boot ROM.
+pub fn reboot() -> ! {
+    #[cfg(feature = "sbi")]
+    sbi::system_reset(sbi::RESET_COLD_REBOOT, sbi::RESET_REASON_NONE);
+    write_reg(FINISHER_RESET);
+    CurrentArch::halt()
+}
+
+/// Handler for `Syscall::SysHalt`. There is no syscall dispatch yet, so
+/// nothing calls this.
+pub fn sys_halt(code: u16) -> ! {
+    poweroff(code)
+}
+
+/// Handler for `Syscall::SysReboot`. Not called yet either.
+pub fn sys_reboot() -> ! {
+    reboot()
+}
+
+fn write_reg(val: u32) {
+    unsafe { (memlayout::VIRT_TEST as *mut u32).write_volatile(val) };
+}
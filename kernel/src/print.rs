@@ -1,6 +1,7 @@
 /// Formatted console output functions.
 use crate::arch::{Arch, CurrentArch};
 use crate::kstate;
+use crate::poweroff;
 
 /// Print to the console.
 pub macro print($($arg:tt)*) {
@@ -56,6 +57,10 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
     // Freeze output from other CPUs
     kstate::set_panicked();
+    if cfg!(feature = "poweroff-on-panic") {
+        // Exit qemu with a failure code instead of spinning forever.
+        poweroff::poweroff(1);
+    }
     // Halt the CPU
     loop {
         CurrentArch::halt();
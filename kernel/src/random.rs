@@ -0,0 +1,171 @@
+/// Kernel entropy pool and random number generator.
+///
+/// Entropy from the virtio-rng device and from timer jitter is mixed into a
+/// 256-bit key. Output is the ChaCha20 keystream under that key. After every
+/// request the key is replaced with fresh keystream, so earlier output can't
+/// be reconstructed from the current state.
+///
+/// Timer jitter alone is too predictable to seed the key. Until the device has
+/// contributed `SEED_BYTES`, `getrandom()` refuses to produce output.
+use crate::arch::{Arch, CurrentArch};
+use crate::drivers::virtio::rng::virtio_rng_read;
+use crate::spinlock::Spinlock;
+use core::cell::Cell;
+
+// Reseed from the device after this many output bytes.
+const RESEED_INTERVAL: usize = 1 << 16;
+// Bytes from the device the key needs before it is considered seeded, as
+// many as the key has.
+const SEED_BYTES: usize = 32;
+
+/// Fill `buf` with random bytes. Backs the `SysGetrandom` system call.
+/// Returns false, leaving `buf` alone, while the pool isn't seeded, e.g.
+/// because there is no virtio-rng device.
+pub fn getrandom(buf: &mut [u8]) -> bool {
+    if POOL.lock.with_lock(|| {
+        POOL.since_reseed.get() >= RESEED_INTERVAL || POOL.device_bytes.get() < SEED_BYTES
+    }) {
+        reseed();
+    }
+    POOL.lock.with_lock(|| {
+        if POOL.device_bytes.get() < SEED_BYTES {
+            return false;
+        }
+        // The exact time of every request is a little unpredictable.
+        POOL.mix(&CurrentArch::time().to_le_bytes());
+
+        let mut block = [0u8; 64];
+        for chunk in buf.chunks_mut(64) {
+            POOL.next_block(&mut block);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        // Fast key erasure: rekey from output nobody has seen.
+        POOL.next_block(&mut block);
+        let mut key = [0u32; 8];
+        for (i, w) in key.iter_mut().enumerate() {
+            *w = u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        POOL.key.set(key);
+        POOL.since_reseed
+            .set(POOL.since_reseed.get().saturating_add(buf.len()));
+        true
+    })
+}
+
+/// Mix `data` into the entropy pool. It need not be uniformly random; every
+/// unpredictable bit helps.
+pub fn add_entropy(data: &[u8]) {
+    POOL.lock.with_lock(|| POOL.mix(data));
+}
+
+/// Pull fresh entropy from the virtio-rng device, if there is one, along with
+/// timer jitter from reading it.
+pub fn reseed() {
+    let mut seed = [0u8; 32];
+    let before = CurrentArch::time();
+    let n = virtio_rng_read(&mut seed);
+    let after = CurrentArch::time();
+    POOL.lock.with_lock(|| {
+        POOL.mix(&seed[..n]);
+        POOL.mix(&(after.wrapping_sub(before)).to_le_bytes());
+        POOL.device_bytes
+            .set(POOL.device_bytes.get().saturating_add(n));
+        POOL.since_reseed.set(0);
+    });
+}
+
+struct Pool {
+    lock: Spinlock,
+    // ChaCha20 key.
+    key: Cell<[u32; 8]>,
+    // Block counter for the current key.
+    counter: Cell<u64>,
+    // Where the next mixed-in byte goes in `key`.
+    mix_pos: Cell<usize>,
+    // Output bytes since the last reseed from the device. Starts saturated so
+    // the first request reseeds.
+    since_reseed: Cell<usize>,
+    // Bytes mixed in from the device so far.
+    device_bytes: Cell<usize>,
+}
+
+unsafe impl Sync for Pool {}
+
+static POOL: Pool = Pool {
+    lock: Spinlock::new("random"),
+    key: Cell::new([0; 8]),
+    counter: Cell::new(0),
+    mix_pos: Cell::new(0),
+    since_reseed: Cell::new(RESEED_INTERVAL),
+    device_bytes: Cell::new(0),
+};
+
+impl Pool {
+    // XOR `data` into the key, then stir it through one ChaCha20 block so
+    // every input bit affects every key bit. Caller must hold the lock.
+    fn mix(&self, data: &[u8]) {
+        let mut key = self.key.get();
+        let mut pos = self.mix_pos.get();
+        for &b in data {
+            key[pos / 4 % 8] ^= (b as u32) << ((pos % 4) * 8);
+            pos = (pos + 1) % 32;
+        }
+        self.mix_pos.set(pos);
+        self.key.set(key);
+
+        let mut block = [0u8; 64];
+        self.next_block(&mut block);
+        for (i, w) in key.iter_mut().enumerate() {
+            *w = u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        self.key.set(key);
+    }
+
+    // Write the next keystream block to `out`. Caller must hold the lock.
+    fn next_block(&self, out: &mut [u8; 64]) {
+        let counter = self.counter.get();
+        self.counter.set(counter.wrapping_add(1));
+        chacha20_block(&self.key.get(), counter, out);
+    }
+}
+
+// The ChaCha20 block function (RFC 8439) with a 64-bit block counter and zero
+// nonce.
+fn chacha20_block(key: &[u32; 8], counter: u64, out: &mut [u8; 64]) {
+    // "expand 32-byte k"
+    let mut init = [0u32; 16];
+    init[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    init[4..12].copy_from_slice(key);
+    init[12] = counter as u32;
+    init[13] = (counter >> 32) as u32;
+
+    let mut s = init;
+    for _ in 0..10 {
+        // Column rounds.
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        // Diagonal rounds.
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    for i in 0..16 {
+        let w = s[i].wrapping_add(init[i]);
+        out[i * 4..i * 4 + 4].copy_from_slice(&w.to_le_bytes());
+    }
+}
+
+#[inline(always)]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
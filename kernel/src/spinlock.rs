@@ -2,7 +2,7 @@ use crate::arch::{Arch, CurrentArch};
 use crate::kutils;
 use core::cell::Cell;
 use core::hint::spin_loop;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 pub struct SpinlockToken {
     _private: (),
@@ -19,6 +19,25 @@ pub struct Spinlock {
     name: &'static str,
     // The cpuid holding the lock.
     cpuid: Cell<Option<usize>>,
+    // For profiling:
+    counters: Counters,
+}
+
+// Profiling counters of a `Spinlock`. Aligned so they sit on a cache line of
+// their own, and counting doesn't disturb cpus spinning on `locked`.
+#[repr(align(64))]
+struct Counters {
+    // Number of times the lock has been acquired.
+    nacquire: AtomicUsize,
+    // Number of times `acquire()` found the lock held by another cpu.
+    ncontended: AtomicUsize,
+}
+
+/// Contention counters of a `Spinlock`.
+#[derive(Copy, Clone)]
+pub struct LockStats {
+    pub acquires: usize,
+    pub contended: usize,
 }
 
 unsafe impl Sync for Spinlock {}
@@ -29,6 +48,19 @@ impl Spinlock {
             locked: AtomicBool::new(false),
             name,
             cpuid: Cell::new(None),
+            counters: Counters {
+                nacquire: AtomicUsize::new(0),
+                ncontended: AtomicUsize::new(0),
+            },
+        }
+    }
+
+    /// Returns how often the lock was acquired and how often acquirers had to
+    /// wait for it.
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            acquires: self.counters.nacquire.load(Ordering::Relaxed),
+            contended: self.counters.ncontended.load(Ordering::Relaxed),
         }
     }
 
@@ -53,9 +85,13 @@ impl Spinlock {
         }
 
         // Use atomic swap instruction.
-        while self.locked.swap(true, Ordering::Acquire) {
-            spin_loop();
+        if self.locked.swap(true, Ordering::Acquire) {
+            self.counters.ncontended.fetch_add(1, Ordering::Relaxed);
+            while self.locked.swap(true, Ordering::Acquire) {
+                spin_loop();
+            }
         }
+        self.counters.nacquire.fetch_add(1, Ordering::Relaxed);
 
         self.cpuid.set(Some(CurrentArch::cpuid()));
         SpinlockToken { _private: () }
@@ -2,30 +2,65 @@
 #![no_main]
 // Enable modern macro syntax.
 #![feature(decl_macro)]
+// Report kernel heap exhaustion with the failing request size.
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
 
 mod arch;
+mod buddy;
 mod buf;
 mod channel;
 mod console;
 mod cpu;
 mod drivers;
 mod elf;
+mod fdt;
 mod kalloc;
+mod kheap;
 mod kmain;
 mod kstate;
 mod kutils;
 mod memlayout;
+mod net;
 mod param;
 mod plic;
+mod poweroff;
 mod print;
 mod proc;
+mod random;
 mod spinlock;
 mod trap;
 
-core::arch::global_asm!(include_str!("asm/entry.S"), start = sym start);
+#[cfg(not(feature = "sbi"))]
+core::arch::global_asm!(
+    include_str!("asm/entry.S"),
+    start = sym start,
+    stack0 = sym STACK0,
+    ncpu = const param::NCPU,
+);
+
+// Under OpenSBI the kernel starts in supervisor mode, see asm/entry_sbi.S.
+#[cfg(feature = "sbi")]
+core::arch::global_asm!(
+    include_str!("asm/entry_sbi.S"),
+    start = sym start,
+    stack0 = sym STACK0,
+    ncpu = const param::NCPU,
+    eid_hsm = const arch::sbi::EID_HSM,
+    hsm_hart_start = const arch::sbi::HSM_HART_START,
+    hsm_hart_stop = const arch::sbi::HSM_HART_STOP,
+);
+
+// asm/entry.S needs a stack so that Rust can run, 4096 bytes per CPU.
+#[repr(C, align(16))]
+struct Stack0([u8; 4096 * param::NCPU]);
+
+static mut STACK0: Stack0 = Stack0([0; 4096 * param::NCPU]);
 
-// asm/entry.S:_entry jumps here in machine mode on stack0.
-fn start() -> ! {
+// asm/entry.S:_entry jumps here in machine mode on stack0, with the address
+// of the device tree blob. asm/entry_sbi.S does the same in supervisor mode.
+fn start(dtb: usize) -> ! {
     use arch::Arch;
-    arch::CurrentArch::start(kmain::kmain)
+    arch::CurrentArch::start(kmain::kmain, dtb)
 }
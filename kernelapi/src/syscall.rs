@@ -21,6 +21,9 @@ enum Syscall {
     SysLink = 19,
     SysMkdir = 20,
     SysClose = 21,
+    SysHalt = 22,
+    SysReboot = 23,
+    SysGetrandom = 24,
 }
 
 pub fn write(_: i32) {